@@ -0,0 +1,247 @@
+#ifndef GVM_H
+#define GVM_H
+
+/* Generated by cbindgen from ffi/src/lib.rs - do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Every packet sent to a light is this many bytes long
+ */
+#define GVM_PACKET_LEN 12
+
+/**
+ * Values for the `mode` arguments and fields, passed as `uint8_t`
+ */
+enum GvmMode {
+  GVM_MODE_HSI = 0,
+  GVM_MODE_CCT = 1,
+  GVM_MODE_SCENE = 2,
+};
+typedef uint8_t GvmMode;
+
+/**
+ * Values for the `scene` arguments and fields, passed as `uint8_t`. These are
+ * the ids the light itself uses.
+ */
+enum GvmScene {
+  GVM_SCENE_LIGHTNING = 1,
+  GVM_SCENE_COP_CAR = 2,
+  GVM_SCENE_CANDLE = 3,
+  GVM_SCENE_TV = 4,
+  GVM_SCENE_BAD_BULB = 5,
+  GVM_SCENE_PARTY = 6,
+  GVM_SCENE_DISCO = 7,
+  GVM_SCENE_PAPARAZZI = 8,
+};
+typedef uint8_t GvmScene;
+
+typedef enum GvmStatus {
+  GVM_STATUS_OK = 0,
+  GVM_STATUS_NULL_POINTER,
+  GVM_STATUS_INVALID_ARGUMENT,
+  GVM_STATUS_NOT_FOUND,
+  GVM_STATUS_BLUETOOTH,
+  /**
+   * A bug in the library, which was caught rather than unwinding into C
+   */
+  GVM_STATUS_PANIC,
+} GvmStatus;
+
+/**
+ * A connected light, created by `gvm_connect` and released with
+ * `gvm_light_free`
+ */
+typedef struct GvmLight GvmLight;
+
+/**
+ * Everything that can be set on a light
+ */
+typedef struct GvmLightState {
+  bool enabled;
+  /**
+   * A `GvmMode`
+   */
+  uint8_t mode;
+  /**
+   * Range: [0, 0x53)
+   */
+  uint8_t hue;
+  /**
+   * Range: [0, 100]
+   */
+  uint8_t saturation;
+  /**
+   * Range: [0, 100]
+   */
+  uint8_t intensity;
+  /**
+   * 100s of Kelvin - Range: [32, 56]
+   */
+  uint8_t temperature;
+  /**
+   * A `GvmScene`
+   */
+  uint8_t scene;
+  /**
+   * 100s of ms between scene transitions - Range: [1, 50]
+   */
+  uint8_t scene_interval;
+} GvmLightState;
+
+/**
+ * A description of the last failure on this thread, or NULL if nothing has
+ * failed yet. The string is owned by the library and stays valid until the
+ * next failing call on the same thread.
+ */
+const char *gvm_last_error(void);
+
+/**
+ * Write the checksum stored in the last two bytes of every packet, big
+ * endian, to `out`. `data` may only be NULL if `len` is 0.
+ *
+ * # Safety
+ *
+ * `data` must point to `len` readable bytes and `out` must be writable.
+ */
+enum GvmStatus gvm_crc16(const uint8_t *data, size_t len, uint16_t *out);
+
+/**
+ * # Safety
+ *
+ * `out` must point to `GVM_PACKET_LEN` writable bytes.
+ */
+enum GvmStatus gvm_encode_power(bool on, uint8_t *out);
+
+/**
+ * `mode` is a `GvmMode`
+ *
+ * # Safety
+ *
+ * `out` must point to `GVM_PACKET_LEN` writable bytes.
+ */
+enum GvmStatus gvm_encode_mode(uint8_t mode, uint8_t *out);
+
+/**
+ * # Safety
+ *
+ * `out` must point to `GVM_PACKET_LEN` writable bytes.
+ */
+enum GvmStatus gvm_encode_hue(uint8_t hue, uint8_t *out);
+
+/**
+ * # Safety
+ *
+ * `out` must point to `GVM_PACKET_LEN` writable bytes.
+ */
+enum GvmStatus gvm_encode_saturation(uint8_t saturation, uint8_t *out);
+
+/**
+ * # Safety
+ *
+ * `out` must point to `GVM_PACKET_LEN` writable bytes.
+ */
+enum GvmStatus gvm_encode_intensity(uint8_t intensity, uint8_t *out);
+
+/**
+ * Color temperature in 100s of Kelvin
+ *
+ * # Safety
+ *
+ * `out` must point to `GVM_PACKET_LEN` writable bytes.
+ */
+enum GvmStatus gvm_encode_temperature(uint8_t temperature, uint8_t *out);
+
+/**
+ * `scene` is a `GvmScene`
+ *
+ * # Safety
+ *
+ * `out` must point to `GVM_PACKET_LEN` writable bytes.
+ */
+enum GvmStatus gvm_encode_scene(uint8_t scene, uint8_t *out);
+
+/**
+ * Time between scene transitions in 100s of ms
+ *
+ * # Safety
+ *
+ * `out` must point to `GVM_PACKET_LEN` writable bytes.
+ */
+enum GvmStatus gvm_encode_scene_interval(uint8_t interval, uint8_t *out);
+
+/**
+ * The state a light is assumed to be in before anything is set
+ */
+struct GvmLightState gvm_light_state_default(void);
+
+/**
+ * Scan until the light with the MAC address `mac` (e.g. `"A4:C1:38:00:11:22"`)
+ * is connected and store it in `out`. Returns `GVM_STATUS_NOT_FOUND` if it
+ * isn't found within `timeout_ms`.
+ *
+ * # Safety
+ *
+ * `mac` must be a NUL terminated string and `out` must be writable.
+ */
+enum GvmStatus gvm_connect(const char *mac, uint32_t timeout_ms, struct GvmLight **out);
+
+/**
+ * Disconnect from a light and free it. Does nothing if `light` is NULL.
+ *
+ * # Safety
+ *
+ * `light` must have come from `gvm_connect` and not already been freed.
+ */
+void gvm_light_free(struct GvmLight *light);
+
+/**
+ * Write everything in `state` that differs from what was last written to the
+ * light
+ *
+ * # Safety
+ *
+ * `light` must come from `gvm_connect` and `state` must be readable.
+ */
+enum GvmStatus gvm_set_state(struct GvmLight *light, const struct GvmLightState *state);
+
+/**
+ * # Safety
+ *
+ * `light` must come from `gvm_connect`.
+ */
+enum GvmStatus gvm_power(struct GvmLight *light, bool on);
+
+/**
+ * # Safety
+ *
+ * `light` must come from `gvm_connect`.
+ */
+enum GvmStatus gvm_set_hsi(struct GvmLight *light,
+                           uint8_t hue,
+                           uint8_t saturation,
+                           uint8_t intensity);
+
+/**
+ * Color temperature in 100s of Kelvin
+ *
+ * # Safety
+ *
+ * `light` must come from `gvm_connect`.
+ */
+enum GvmStatus gvm_set_cct(struct GvmLight *light, uint8_t temperature, uint8_t intensity);
+
+/**
+ * `scene` is a `GvmScene`
+ *
+ * # Safety
+ *
+ * `light` must come from `gvm_connect`.
+ */
+enum GvmStatus gvm_set_scene(struct GvmLight *light, uint8_t scene);
+
+#endif /* GVM_H */
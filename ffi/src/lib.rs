@@ -0,0 +1,717 @@
+//! C bindings for the `gvm` light control library. The header is generated
+//! into `include/gvm.h` whenever the crate is built.
+//!
+//! Fallible functions return a `GvmStatus`, and `gvm_last_error` describes the
+//! most recent failure on the calling thread. A panic inside the library is
+//! caught before it reaches the caller and reported as `GVM_STATUS_PANIC`. Bluetooth calls block the
+//! calling thread on a tokio runtime shared by the whole library, so they must
+//! not be made from inside another tokio runtime.
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use gvm::{
+    bluetooth,
+    protocol::{
+        self, ColorTemperatureCommand, HsiCommand, ModeCommand, Packable, PowerCommand, Scene,
+        SceneCommand,
+    },
+    Light, LightMode, LightState, MacAddress,
+};
+use tokio::runtime::Runtime;
+
+/// Every packet sent to a light is this many bytes long
+pub const GVM_PACKET_LEN: usize = 12;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GvmStatus {
+    Ok = 0,
+    NullPointer,
+    InvalidArgument,
+    NotFound,
+    Bluetooth,
+    /// A bug in the library, which was caught rather than unwinding into C
+    Panic,
+}
+
+/// Values for the `mode` arguments and fields, passed as `uint8_t`
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GvmMode {
+    Hsi = 0,
+    Cct = 1,
+    Scene = 2,
+}
+
+/// Values for the `scene` arguments and fields, passed as `uint8_t`. These are
+/// the ids the light itself uses.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GvmScene {
+    Lightning = 1,
+    CopCar = 2,
+    Candle = 3,
+    Tv = 4,
+    BadBulb = 5,
+    Party = 6,
+    Disco = 7,
+    Paparazzi = 8,
+}
+
+/// Everything that can be set on a light
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GvmLightState {
+    pub enabled: bool,
+
+    /// A `GvmMode`
+    pub mode: u8,
+
+    /// Range: [0, 0x53)
+    pub hue: u8,
+
+    /// Range: [0, 100]
+    pub saturation: u8,
+
+    /// Range: [0, 100]
+    pub intensity: u8,
+
+    /// 100s of Kelvin - Range: [32, 56]
+    pub temperature: u8,
+
+    /// A `GvmScene`
+    pub scene: u8,
+
+    /// 100s of ms between scene transitions - Range: [1, 50]
+    pub scene_interval: u8,
+}
+
+/// A connected light, created by `gvm_connect` and released with
+/// `gvm_light_free`
+pub struct GvmLight(Light);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn runtime() -> Result<&'static Runtime, GvmStatus> {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+
+    // Bluetooth calls are the only thing that needs the runtime, so they are
+    // what fails without one
+    let runtime = Runtime::new().map_err(|e| {
+        fail(
+            GvmStatus::Bluetooth,
+            format!("failed to start tokio runtime: {e}"),
+        )
+    })?;
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+fn block_on<F: Future>(future: F) -> Result<F::Output, GvmStatus> {
+    Ok(runtime()?.block_on(future))
+}
+
+/// Run the body of an exported function, returning `on_panic` if it panics
+/// rather than unwinding into C, which is undefined behaviour. The panic is
+/// recorded for `gvm_last_error`.
+fn catch_panic<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        fail(
+            GvmStatus::Panic,
+            format!("panicked: {}", panic_message(&*payload)),
+        );
+        on_panic
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown panic", String::as_str),
+    }
+}
+
+/// Record `message` for `gvm_last_error` and return `status`
+fn fail(status: GvmStatus, message: impl ToString) -> GvmStatus {
+    let message = CString::new(message.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+
+    status
+}
+
+fn bluetooth_error(e: eyre::Report) -> GvmStatus {
+    fail(GvmStatus::Bluetooth, e)
+}
+
+fn parse_mode(mode: u8) -> Result<LightMode, GvmStatus> {
+    match mode {
+        0 => Ok(LightMode::Hsi),
+        1 => Ok(LightMode::Cct),
+        2 => Ok(LightMode::Scene),
+        _ => Err(fail(
+            GvmStatus::InvalidArgument,
+            format!("unknown mode {mode}"),
+        )),
+    }
+}
+
+fn parse_scene(scene: u8) -> Result<Scene, GvmStatus> {
+    Scene::from_id(scene).ok_or_else(|| {
+        fail(
+            GvmStatus::InvalidArgument,
+            format!("unknown scene {scene}, expected 1 to 8"),
+        )
+    })
+}
+
+impl From<&LightState> for GvmLightState {
+    fn from(state: &LightState) -> Self {
+        let mode = match state.mode {
+            LightMode::Hsi => GvmMode::Hsi,
+            LightMode::Cct => GvmMode::Cct,
+            LightMode::Scene => GvmMode::Scene,
+        };
+
+        Self {
+            enabled: state.enabled,
+            mode: mode as u8,
+            hue: state.hue,
+            saturation: state.saturation,
+            intensity: state.intensity,
+            temperature: state.temperature,
+            scene: state.scene.id(),
+            scene_interval: state.scene_interval,
+        }
+    }
+}
+
+impl TryFrom<&GvmLightState> for LightState {
+    type Error = GvmStatus;
+
+    fn try_from(state: &GvmLightState) -> Result<Self, GvmStatus> {
+        Ok(Self {
+            enabled: state.enabled,
+            mode: parse_mode(state.mode)?,
+            hue: state.hue,
+            saturation: state.saturation,
+            intensity: state.intensity,
+            temperature: state.temperature,
+            scene: parse_scene(state.scene)?,
+            scene_interval: state.scene_interval,
+        })
+    }
+}
+
+/// A description of the last failure on this thread, or NULL if nothing has
+/// failed yet. The string is owned by the library and stays valid until the
+/// next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn gvm_last_error() -> *const c_char {
+    catch_panic(ptr::null(), || {
+        LAST_ERROR.with(|last| {
+            last.borrow()
+                .as_ref()
+                .map_or(ptr::null(), |message| message.as_ptr())
+        })
+    })
+}
+
+/// Write the checksum stored in the last two bytes of every packet, big
+/// endian, to `out`. `data` may only be NULL if `len` is 0.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_crc16(data: *const u8, len: usize, out: *mut u16) -> GvmStatus {
+    catch_panic(GvmStatus::Panic, || {
+        if out.is_null() {
+            return fail(GvmStatus::NullPointer, "out is NULL");
+        }
+
+        let data = match (data.is_null(), len) {
+            (_, 0) => &[][..],
+            (true, _) => return fail(GvmStatus::NullPointer, "data is NULL"),
+            (false, _) => std::slice::from_raw_parts(data, len),
+        };
+
+        *out = protocol::crc_16_xmodem(data);
+        GvmStatus::Ok
+    })
+}
+
+/// Write the packet for `command` to `out`, which must have room for
+/// `GVM_PACKET_LEN` bytes
+unsafe fn encode(command: impl Packable, out: *mut u8) -> GvmStatus {
+    catch_panic(GvmStatus::Panic, || {
+        if out.is_null() {
+            return fail(GvmStatus::NullPointer, "out is NULL");
+        }
+
+        let wire = command.to_wire();
+        ptr::copy_nonoverlapping(wire.as_ptr(), out, wire.len());
+
+        GvmStatus::Ok
+    })
+}
+
+/// # Safety
+///
+/// `out` must point to `GVM_PACKET_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_encode_power(on: bool, out: *mut u8) -> GvmStatus {
+    let command = if on {
+        PowerCommand::On
+    } else {
+        PowerCommand::Off
+    };
+
+    encode(command, out)
+}
+
+/// `mode` is a `GvmMode`
+///
+/// # Safety
+///
+/// `out` must point to `GVM_PACKET_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_encode_mode(mode: u8, out: *mut u8) -> GvmStatus {
+    catch_panic(GvmStatus::Panic, || {
+        let command = match parse_mode(mode) {
+            Ok(LightMode::Hsi) => ModeCommand::Hsi,
+            Ok(LightMode::Cct) => ModeCommand::Cct,
+            Ok(LightMode::Scene) => ModeCommand::Scene,
+            Err(status) => return status,
+        };
+
+        encode(command, out)
+    })
+}
+
+/// # Safety
+///
+/// `out` must point to `GVM_PACKET_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_encode_hue(hue: u8, out: *mut u8) -> GvmStatus {
+    encode(HsiCommand::Hue(hue), out)
+}
+
+/// # Safety
+///
+/// `out` must point to `GVM_PACKET_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_encode_saturation(saturation: u8, out: *mut u8) -> GvmStatus {
+    encode(HsiCommand::Saturation(saturation), out)
+}
+
+/// # Safety
+///
+/// `out` must point to `GVM_PACKET_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_encode_intensity(intensity: u8, out: *mut u8) -> GvmStatus {
+    encode(HsiCommand::Intensity(intensity), out)
+}
+
+/// Color temperature in 100s of Kelvin
+///
+/// # Safety
+///
+/// `out` must point to `GVM_PACKET_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_encode_temperature(temperature: u8, out: *mut u8) -> GvmStatus {
+    encode(ColorTemperatureCommand(temperature), out)
+}
+
+/// `scene` is a `GvmScene`
+///
+/// # Safety
+///
+/// `out` must point to `GVM_PACKET_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_encode_scene(scene: u8, out: *mut u8) -> GvmStatus {
+    catch_panic(GvmStatus::Panic, || match parse_scene(scene) {
+        Ok(scene) => encode(SceneCommand::Pick(scene), out),
+        Err(status) => status,
+    })
+}
+
+/// Time between scene transitions in 100s of ms
+///
+/// # Safety
+///
+/// `out` must point to `GVM_PACKET_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_encode_scene_interval(interval: u8, out: *mut u8) -> GvmStatus {
+    encode(SceneCommand::Interval(interval), out)
+}
+
+/// The state a light is assumed to be in before anything is set
+#[no_mangle]
+pub extern "C" fn gvm_light_state_default() -> GvmLightState {
+    let state = || (&LightState::default()).into();
+    catch_panic(state(), state)
+}
+
+/// Scan until the light with the MAC address `mac` (e.g. `"A4:C1:38:00:11:22"`)
+/// is connected and store it in `out`. Returns `GVM_STATUS_NOT_FOUND` if it
+/// isn't found within `timeout_ms`.
+///
+/// # Safety
+///
+/// `mac` must be a NUL terminated string and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_connect(
+    mac: *const c_char,
+    timeout_ms: u32,
+    out: *mut *mut GvmLight,
+) -> GvmStatus {
+    catch_panic(GvmStatus::Panic, || {
+        if mac.is_null() || out.is_null() {
+            return fail(GvmStatus::NullPointer, "mac or out is NULL");
+        }
+
+        let mac: MacAddress = match CStr::from_ptr(mac).to_str().map(str::parse) {
+            Ok(Ok(mac)) => mac,
+            Ok(Err(e)) => return fail(GvmStatus::InvalidArgument, e),
+            Err(e) => return fail(GvmStatus::InvalidArgument, e),
+        };
+
+        let timeout = Duration::from_millis(timeout_ms.into());
+        match block_on(bluetooth::find(mac, &[], timeout)) {
+            Ok(Ok(Some(light))) => {
+                *out = Box::into_raw(Box::new(GvmLight(light)));
+                GvmStatus::Ok
+            }
+            Ok(Ok(None)) => fail(GvmStatus::NotFound, format!("light {mac:?} was not found")),
+            Ok(Err(e)) => bluetooth_error(e),
+            Err(status) => status,
+        }
+    })
+}
+
+/// Disconnect from a light and free it. Does nothing if `light` is NULL.
+///
+/// # Safety
+///
+/// `light` must have come from `gvm_connect` and not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_light_free(light: *mut GvmLight) {
+    catch_panic((), || {
+        if light.is_null() {
+            return;
+        }
+
+        let light = Box::from_raw(light);
+        let _ = block_on(light.0.disconnect());
+    })
+}
+
+/// Run `f` on a light handle, failing if it is NULL
+unsafe fn with_light(
+    light: *mut GvmLight,
+    f: impl FnOnce(&mut Light) -> Result<(), GvmStatus>,
+) -> GvmStatus {
+    catch_panic(GvmStatus::Panic, || {
+        let Some(light) = light.as_mut() else {
+            return fail(GvmStatus::NullPointer, "light is NULL");
+        };
+
+        match f(&mut light.0) {
+            Ok(()) => GvmStatus::Ok,
+            Err(status) => status,
+        }
+    })
+}
+
+/// Write everything in `state` that differs from what was last written to the
+/// light
+///
+/// # Safety
+///
+/// `light` must come from `gvm_connect` and `state` must be readable.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_set_state(
+    light: *mut GvmLight,
+    state: *const GvmLightState,
+) -> GvmStatus {
+    catch_panic(GvmStatus::Panic, || {
+        let Some(state) = state.as_ref() else {
+            return fail(GvmStatus::NullPointer, "state is NULL");
+        };
+
+        with_light(light, |light| {
+            let state = LightState::try_from(state)?;
+            block_on(light.set_state(&state))?.map_err(bluetooth_error)
+        })
+    })
+}
+
+/// # Safety
+///
+/// `light` must come from `gvm_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_power(light: *mut GvmLight, on: bool) -> GvmStatus {
+    with_light(light, |light| {
+        block_on(light.power(on))?.map_err(bluetooth_error)
+    })
+}
+
+/// # Safety
+///
+/// `light` must come from `gvm_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_set_hsi(
+    light: *mut GvmLight,
+    hue: u8,
+    saturation: u8,
+    intensity: u8,
+) -> GvmStatus {
+    with_light(light, |light| {
+        block_on(light.set_hsi(hue, saturation, intensity))?.map_err(bluetooth_error)
+    })
+}
+
+/// Color temperature in 100s of Kelvin
+///
+/// # Safety
+///
+/// `light` must come from `gvm_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_set_cct(
+    light: *mut GvmLight,
+    temperature: u8,
+    intensity: u8,
+) -> GvmStatus {
+    with_light(light, |light| {
+        block_on(light.set_cct(temperature, intensity))?.map_err(bluetooth_error)
+    })
+}
+
+/// `scene` is a `GvmScene`
+///
+/// # Safety
+///
+/// `light` must come from `gvm_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn gvm_set_scene(light: *mut GvmLight, scene: u8) -> GvmStatus {
+    with_light(light, |light| {
+        let scene = parse_scene(scene)?;
+        block_on(light.set_scene(scene))?.map_err(bluetooth_error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::WireMessage;
+
+    use super::*;
+
+    fn last_error() -> String {
+        let message = gvm_last_error();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Call an encoder, checking it succeeds
+    fn encoded(encode: unsafe extern "C" fn(u8, *mut u8) -> GvmStatus, value: u8) -> WireMessage {
+        let mut out = [0; GVM_PACKET_LEN];
+        assert_eq!(unsafe { encode(value, out.as_mut_ptr()) }, GvmStatus::Ok);
+        out
+    }
+
+    #[test]
+    fn encoders_match_the_protocol() {
+        let mut out = [0; GVM_PACKET_LEN];
+        assert_eq!(
+            unsafe { gvm_encode_power(true, out.as_mut_ptr()) },
+            GvmStatus::Ok
+        );
+        assert_eq!(out, PowerCommand::On.to_wire());
+        assert_eq!(
+            unsafe { gvm_encode_power(false, out.as_mut_ptr()) },
+            GvmStatus::Ok
+        );
+        assert_eq!(out, PowerCommand::Off.to_wire());
+
+        assert_eq!(
+            encoded(gvm_encode_mode, GvmMode::Hsi as u8),
+            ModeCommand::Hsi.to_wire()
+        );
+        assert_eq!(
+            encoded(gvm_encode_mode, GvmMode::Cct as u8),
+            ModeCommand::Cct.to_wire()
+        );
+        assert_eq!(
+            encoded(gvm_encode_mode, GvmMode::Scene as u8),
+            ModeCommand::Scene.to_wire()
+        );
+        assert_eq!(encoded(gvm_encode_hue, 40), HsiCommand::Hue(40).to_wire());
+        assert_eq!(
+            encoded(gvm_encode_saturation, 70),
+            HsiCommand::Saturation(70).to_wire()
+        );
+        assert_eq!(
+            encoded(gvm_encode_intensity, 30),
+            HsiCommand::Intensity(30).to_wire()
+        );
+        assert_eq!(
+            encoded(gvm_encode_temperature, 45),
+            ColorTemperatureCommand(45).to_wire()
+        );
+        assert_eq!(
+            encoded(gvm_encode_scene, GvmScene::Candle as u8),
+            SceneCommand::Pick(Scene::Candle).to_wire()
+        );
+        assert_eq!(
+            encoded(gvm_encode_scene_interval, 12),
+            SceneCommand::Interval(12).to_wire()
+        );
+    }
+
+    #[test]
+    fn crc16_matches_the_protocol() {
+        let data = [0x4c, 0x54, 0x09, 0x00, 0x30, 0x57, 0x00, 0x05, 0x01, 0x05];
+        let mut crc = 0;
+        assert_eq!(
+            unsafe { gvm_crc16(data.as_ptr(), data.len(), &mut crc) },
+            GvmStatus::Ok
+        );
+        assert_eq!(crc, protocol::crc_16_xmodem(&data));
+
+        assert_eq!(
+            unsafe { gvm_crc16(ptr::null(), 0, &mut crc) },
+            GvmStatus::Ok
+        );
+        assert_eq!(crc, protocol::crc_16_xmodem(&[]));
+    }
+
+    #[test]
+    fn null_arguments_are_rejected() {
+        assert_eq!(
+            unsafe { gvm_encode_hue(1, ptr::null_mut()) },
+            GvmStatus::NullPointer
+        );
+        assert_eq!(last_error(), "out is NULL");
+
+        let mut crc = 0;
+        assert_eq!(
+            unsafe { gvm_crc16(ptr::null(), 4, &mut crc) },
+            GvmStatus::NullPointer
+        );
+        assert_eq!(last_error(), "data is NULL");
+        assert_eq!(
+            unsafe { gvm_crc16([1].as_ptr(), 1, ptr::null_mut()) },
+            GvmStatus::NullPointer
+        );
+
+        let mut light = ptr::null_mut();
+        assert_eq!(
+            unsafe { gvm_connect(ptr::null(), 0, &mut light) },
+            GvmStatus::NullPointer
+        );
+        assert_eq!(last_error(), "mac or out is NULL");
+
+        assert_eq!(
+            unsafe { gvm_power(ptr::null_mut(), true) },
+            GvmStatus::NullPointer
+        );
+        assert_eq!(last_error(), "light is NULL");
+
+        let state = gvm_light_state_default();
+        assert_eq!(
+            unsafe { gvm_set_state(ptr::null_mut(), &state) },
+            GvmStatus::NullPointer
+        );
+        assert_eq!(
+            unsafe { gvm_set_state(ptr::null_mut(), ptr::null()) },
+            GvmStatus::NullPointer
+        );
+        assert_eq!(last_error(), "state is NULL");
+
+        // Freeing NULL is allowed
+        unsafe { gvm_light_free(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        let mut out = [0; GVM_PACKET_LEN];
+        assert_eq!(
+            unsafe { gvm_encode_mode(3, out.as_mut_ptr()) },
+            GvmStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "unknown mode 3");
+
+        for scene in [0, 9] {
+            assert_eq!(
+                unsafe { gvm_encode_scene(scene, out.as_mut_ptr()) },
+                GvmStatus::InvalidArgument
+            );
+            assert_eq!(
+                last_error(),
+                format!("unknown scene {scene}, expected 1 to 8")
+            );
+        }
+
+        // Nothing was written by the failed calls
+        assert_eq!(out, [0; GVM_PACKET_LEN]);
+
+        let mut light = ptr::null_mut();
+        assert_eq!(
+            unsafe { gvm_connect(c"not a mac".as_ptr(), 0, &mut light) },
+            GvmStatus::InvalidArgument
+        );
+        assert!(light.is_null());
+    }
+
+    #[test]
+    fn states_convert_both_ways() {
+        let state = LightState {
+            mode: LightMode::Scene,
+            scene: Scene::Disco,
+            hue: 20,
+            ..Default::default()
+        };
+        let ffi = GvmLightState::from(&state);
+        assert_eq!(ffi.mode, GvmMode::Scene as u8);
+        assert_eq!(ffi.scene, GvmScene::Disco as u8);
+        assert_eq!(LightState::try_from(&ffi), Ok(state));
+
+        let invalid = GvmLightState { scene: 0, ..ffi };
+        assert_eq!(
+            LightState::try_from(&invalid),
+            Err(GvmStatus::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn panics_are_caught() {
+        let status = catch_panic(GvmStatus::Panic, || GvmStatus::NotFound);
+        assert_eq!(status, GvmStatus::NotFound);
+
+        let status = catch_panic(GvmStatus::Panic, || panic!("oops"));
+        assert_eq!(status, GvmStatus::Panic);
+        assert_eq!(last_error(), "panicked: oops");
+
+        let message: *const c_char = catch_panic(ptr::null(), || panic!("{} went wrong", 2));
+        assert!(message.is_null());
+        assert_eq!(last_error(), "panicked: 2 went wrong");
+    }
+}
@@ -0,0 +1,413 @@
+//! Python bindings for the `gvm` light control library.
+//!
+//! Bluetooth operations block the calling thread (with the GIL released) on a
+//! tokio runtime shared by the whole module. The protocol encoder and decoder
+//! never touch a radio.
+
+use std::{future::Future, sync::OnceLock, time::Duration};
+
+use gvm::{
+    bluetooth,
+    protocol::{
+        self, ColorTemperatureCommand, Command, HsiCommand, ModeCommand, Packable, PowerCommand,
+        Scene, SceneCommand,
+    },
+    Light, LightMode, LightState, MacAddress,
+};
+use pyo3::{
+    exceptions::{PyLookupError, PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+use tokio::runtime::Runtime;
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Runtime::new().expect("failed to start tokio runtime"))
+}
+
+/// Run a future to completion on the shared runtime, letting other Python
+/// threads run in the meantime
+fn block_on<F>(py: Python<'_>, future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    py.allow_threads(|| runtime().block_on(future))
+}
+
+fn runtime_error(e: eyre::Report) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+fn timeout(seconds: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+fn parse_scene(scene: &str) -> PyResult<Scene> {
+    scene.parse().map_err(PyValueError::new_err)
+}
+
+fn parse_mode(mode: &str) -> PyResult<LightMode> {
+    match mode.to_lowercase().as_str() {
+        "hsi" => Ok(LightMode::Hsi),
+        "cct" => Ok(LightMode::Cct),
+        "scene" => Ok(LightMode::Scene),
+        _ => Err(PyValueError::new_err(format!(
+            "unknown mode '{mode}', expected hsi, cct or scene"
+        ))),
+    }
+}
+
+fn mode_name(mode: &LightMode) -> &'static str {
+    match mode {
+        LightMode::Hsi => "hsi",
+        LightMode::Cct => "cct",
+        LightMode::Scene => "scene",
+    }
+}
+
+/// A single command understood by the light
+#[pyclass(name = "Command", frozen, eq)]
+#[derive(Clone, PartialEq)]
+struct PyCommand(Command);
+
+#[pymethods]
+impl PyCommand {
+    #[staticmethod]
+    fn power(on: bool) -> Self {
+        Self(Command::Power(if on {
+            PowerCommand::On
+        } else {
+            PowerCommand::Off
+        }))
+    }
+
+    /// Switch between `"hsi"`, `"cct"` and `"scene"` mode
+    #[staticmethod]
+    fn mode(mode: &str) -> PyResult<Self> {
+        let mode = match parse_mode(mode)? {
+            LightMode::Hsi => ModeCommand::Hsi,
+            LightMode::Cct => ModeCommand::Cct,
+            LightMode::Scene => ModeCommand::Scene,
+        };
+
+        Ok(Self(Command::Mode(mode)))
+    }
+
+    #[staticmethod]
+    fn hue(hue: u8) -> Self {
+        Self(Command::Hsi(HsiCommand::Hue(hue)))
+    }
+
+    #[staticmethod]
+    fn saturation(saturation: u8) -> Self {
+        Self(Command::Hsi(HsiCommand::Saturation(saturation)))
+    }
+
+    #[staticmethod]
+    fn intensity(intensity: u8) -> Self {
+        Self(Command::Hsi(HsiCommand::Intensity(intensity)))
+    }
+
+    /// Color temperature in 100s of Kelvin
+    #[staticmethod]
+    fn temperature(temperature: u8) -> Self {
+        Self(Command::ColorTemperature(ColorTemperatureCommand(
+            temperature,
+        )))
+    }
+
+    #[staticmethod]
+    fn scene(scene: &str) -> PyResult<Self> {
+        Ok(Self(Command::Scene(SceneCommand::Pick(parse_scene(
+            scene,
+        )?))))
+    }
+
+    /// Time between scene transitions in 100s of ms
+    #[staticmethod]
+    fn scene_interval(interval: u8) -> Self {
+        Self(Command::Scene(SceneCommand::Interval(interval)))
+    }
+
+    /// The 12 byte packet to send to the light
+    fn to_wire<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.to_wire())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Command({:?})", self.0)
+    }
+}
+
+/// Parse a packet sent to or received from a light, raising `ValueError` if it
+/// isn't a valid command
+#[pyfunction]
+fn decode(data: &[u8]) -> PyResult<PyCommand> {
+    protocol::decode(data)
+        .map(PyCommand)
+        .map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+/// Everything that can be set on a light. Anything not passed in keeps its
+/// default.
+#[pyclass(name = "LightState", frozen, eq)]
+#[derive(Clone, PartialEq)]
+struct PyLightState(LightState);
+
+#[pymethods]
+impl PyLightState {
+    #[new]
+    #[pyo3(signature = (
+        *,
+        mode = None,
+        enabled = None,
+        hue = None,
+        saturation = None,
+        intensity = None,
+        temperature = None,
+        scene = None,
+        scene_interval = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        mode: Option<&str>,
+        enabled: Option<bool>,
+        hue: Option<u8>,
+        saturation: Option<u8>,
+        intensity: Option<u8>,
+        temperature: Option<u8>,
+        scene: Option<&str>,
+        scene_interval: Option<u8>,
+    ) -> PyResult<Self> {
+        let mut state = LightState::default();
+
+        if let Some(mode) = mode {
+            state.mode = parse_mode(mode)?;
+        }
+        if let Some(scene) = scene {
+            state.scene = parse_scene(scene)?;
+        }
+        state.enabled = enabled.unwrap_or(state.enabled);
+        state.hue = hue.unwrap_or(state.hue);
+        state.saturation = saturation.unwrap_or(state.saturation);
+        state.intensity = intensity.unwrap_or(state.intensity);
+        state.temperature = temperature.unwrap_or(state.temperature);
+        state.scene_interval = scene_interval.unwrap_or(state.scene_interval);
+
+        Ok(Self(state))
+    }
+
+    #[getter]
+    fn mode(&self) -> &'static str {
+        mode_name(&self.0.mode)
+    }
+
+    #[getter]
+    fn enabled(&self) -> bool {
+        self.0.enabled
+    }
+
+    #[getter]
+    fn hue(&self) -> u8 {
+        self.0.hue
+    }
+
+    #[getter]
+    fn saturation(&self) -> u8 {
+        self.0.saturation
+    }
+
+    #[getter]
+    fn intensity(&self) -> u8 {
+        self.0.intensity
+    }
+
+    #[getter]
+    fn temperature(&self) -> u8 {
+        self.0.temperature
+    }
+
+    #[getter]
+    fn scene(&self) -> &'static str {
+        self.0.scene.name()
+    }
+
+    #[getter]
+    fn scene_interval(&self) -> u8 {
+        self.0.scene_interval
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.0)
+    }
+}
+
+/// A connected light
+#[pyclass(name = "Light")]
+struct PyLight(Light);
+
+#[pymethods]
+impl PyLight {
+    /// The light's MAC address where known, otherwise its bluetooth id
+    #[getter]
+    fn id(&self) -> String {
+        self.0.id()
+    }
+
+    #[getter]
+    fn mac(&self) -> Option<String> {
+        match self.0.mac() {
+            mac @ MacAddress::Known(_) => Some(format!("{mac:?}")),
+            MacAddress::Unknown => None,
+        }
+    }
+
+    /// The state last written to the light, or `None` if nothing has been
+    /// written yet
+    #[getter]
+    fn state(&self) -> Option<PyLightState> {
+        self.0.state().cloned().map(PyLightState)
+    }
+
+    fn set_state(&mut self, py: Python<'_>, state: PyLightState) -> PyResult<()> {
+        block_on(py, self.0.set_state(&state.0)).map_err(runtime_error)
+    }
+
+    fn power(&mut self, py: Python<'_>, on: bool) -> PyResult<()> {
+        block_on(py, self.0.power(on)).map_err(runtime_error)
+    }
+
+    fn set_hsi(&mut self, py: Python<'_>, hue: u8, saturation: u8, intensity: u8) -> PyResult<()> {
+        block_on(py, self.0.set_hsi(hue, saturation, intensity)).map_err(runtime_error)
+    }
+
+    /// Color temperature in 100s of Kelvin
+    fn set_cct(&mut self, py: Python<'_>, temperature: u8, intensity: u8) -> PyResult<()> {
+        block_on(py, self.0.set_cct(temperature, intensity)).map_err(runtime_error)
+    }
+
+    fn set_scene(&mut self, py: Python<'_>, scene: &str) -> PyResult<()> {
+        let scene = parse_scene(scene)?;
+        block_on(py, self.0.set_scene(scene)).map_err(runtime_error)
+    }
+
+    /// Send a single raw command
+    fn send(&self, py: Python<'_>, command: PyCommand) -> PyResult<()> {
+        block_on(py, self.0.cmd(command.0)).map_err(runtime_error)
+    }
+
+    fn disconnect(&self, py: Python<'_>) -> PyResult<()> {
+        block_on(py, self.0.disconnect()).map_err(runtime_error)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Light({})", self.0.id())
+    }
+}
+
+/// Scan for `timeout` seconds, connecting to every light that is found
+#[pyfunction]
+#[pyo3(signature = (timeout = 5.0))]
+fn discover(py: Python<'_>, timeout: f64) -> PyResult<Vec<PyLight>> {
+    let lights =
+        block_on(py, bluetooth::discover(&[], self::timeout(timeout)?)).map_err(runtime_error)?;
+
+    Ok(lights.into_iter().map(PyLight).collect())
+}
+
+/// Scan until the light with the given MAC address is connected, raising
+/// `LookupError` if it isn't found within `timeout` seconds
+#[pyfunction]
+#[pyo3(signature = (mac, timeout = 5.0))]
+fn connect(py: Python<'_>, mac: &str, timeout: f64) -> PyResult<PyLight> {
+    let mac: MacAddress = mac
+        .parse()
+        .map_err(|e: eyre::Report| PyValueError::new_err(e.to_string()))?;
+
+    match block_on(py, bluetooth::find(mac, &[], self::timeout(timeout)?)).map_err(runtime_error)? {
+        Some(light) => Ok(PyLight(light)),
+        None => Err(PyLookupError::new_err(format!(
+            "light {mac:?} was not found"
+        ))),
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "gvm")]
+fn gvm_python(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyCommand>()?;
+    module.add_class::<PyLightState>()?;
+    module.add_class::<PyLight>()?;
+    module.add_function(wrap_pyfunction!(decode, module)?)?;
+    module.add_function(wrap_pyfunction!(discover, module)?)?;
+    module.add_function(wrap_pyfunction!(connect, module)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_round_trip_through_decode() {
+        let commands = [
+            PyCommand::power(true),
+            PyCommand::power(false),
+            PyCommand::mode("hsi").unwrap(),
+            PyCommand::mode("CCT").unwrap(),
+            PyCommand::mode("scene").unwrap(),
+            PyCommand::hue(40),
+            PyCommand::saturation(70),
+            PyCommand::intensity(30),
+            PyCommand::temperature(45),
+            PyCommand::scene("candle").unwrap(),
+            PyCommand::scene_interval(12),
+        ];
+
+        for command in commands {
+            let decoded = decode(&command.0.to_wire()).unwrap();
+            assert!(decoded == command, "{}", command.__repr__());
+        }
+    }
+
+    #[test]
+    fn light_state_keywords_default() {
+        let state = PyLightState::new(None, None, None, None, None, None, None, None).unwrap();
+        assert!(state.0 == LightState::default());
+
+        let state = PyLightState::new(
+            Some("hsi"),
+            None,
+            Some(20),
+            None,
+            Some(80),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(
+            state.0
+                == LightState {
+                    mode: LightMode::Hsi,
+                    hue: 20,
+                    intensity: 80,
+                    ..Default::default()
+                }
+        );
+        assert_eq!(state.mode(), "hsi");
+        assert_eq!(state.scene(), LightState::default().scene.name());
+    }
+
+    #[test]
+    fn invalid_modes_and_scenes_are_rejected() {
+        assert!(PyCommand::mode("rgb").is_err());
+        assert!(PyCommand::scene("sunset").is_err());
+        assert!(PyLightState::new(Some("rgb"), None, None, None, None, None, None, None).is_err());
+        assert!(PyLightState::new(None, None, None, None, None, None, Some("x"), None).is_err());
+        assert!(decode(&[0; 12]).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    f32::consts::TAU,
+    io::{BufReader, Read},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use eyre::Result;
+use gvm::LightMode;
+use hound::{SampleFormat, WavReader};
+use tracing::info;
+
+use crate::gui::LightGuiState;
+
+/// Band edges used to split the signal into bass and treble
+const BASS_CUTOFF_HZ: f32 = 200.0;
+const TREBLE_CUTOFF_HZ: f32 = 2000.0;
+
+/// A beat is bass energy this many times louder than its recent average
+const BEAT_THRESHOLD: f32 = 1.5;
+
+/// Seconds over which the recent average of bass energy is taken
+const BEAT_HISTORY_SECONDS: f32 = 1.0;
+
+/// How quickly the loudest level heard decays, per second, so that the
+/// intensity adapts to quieter passages
+const PEAK_DECAY_PER_SECOND: f32 = 0.8;
+
+/// Quietest intensity while music is playing, so the lights never go fully
+/// dark between beats
+const MIN_INTENSITY: f32 = 5.0;
+
+/// Hue for bass heavy sound (red) through to treble heavy sound (blue)
+const BASS_HUE: f32 = 0.0;
+const TREBLE_HUE: f32 = 55.0;
+
+/// Where samples come from
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AudioSource {
+    Wav(PathBuf),
+    /// Raw interleaved signed 16-bit little endian PCM on stdin
+    Stdin,
+}
+
+impl FromStr for AudioSource {
+    type Err = std::convert::Infallible;
+
+    /// `-` is stdin, anything else a WAV file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "-" => Self::Stdin,
+            path => Self::Wav(path.into()),
+        })
+    }
+}
+
+/// Format of raw PCM, which unlike a WAV file doesn't describe itself
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PcmFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// What was heard in a single block of audio
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Analysis {
+    /// Loudness relative to the loudest recent block - Range: [0, 1]
+    level: f32,
+
+    /// Share of the energy in the treble rather than the bass - Range: [0, 1]
+    brightness: f32,
+
+    beat: bool,
+}
+
+/// Tracks the signal across blocks, splitting it into bands with one-pole
+/// filters
+struct Analyzer {
+    sample_rate: f32,
+    bass_filter: f32,
+    treble_filter: f32,
+    peak: f32,
+    bass_average: f32,
+}
+
+impl Analyzer {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate as f32,
+            bass_filter: 0.0,
+            treble_filter: 0.0,
+            peak: 0.0,
+            bass_average: 0.0,
+        }
+    }
+
+    fn analyze(&mut self, block: &[f32]) -> Analysis {
+        let coefficient = |cutoff: f32| 1.0 - (-TAU * cutoff / self.sample_rate).exp();
+        let bass_coefficient = coefficient(BASS_CUTOFF_HZ);
+        let treble_coefficient = coefficient(TREBLE_CUTOFF_HZ);
+
+        let mut total = 0.0;
+        let mut bass = 0.0;
+        let mut treble = 0.0;
+
+        for sample in block {
+            self.bass_filter += bass_coefficient * (sample - self.bass_filter);
+            self.treble_filter += treble_coefficient * (sample - self.treble_filter);
+
+            // Everything above the treble cutoff
+            let high = sample - self.treble_filter;
+
+            total += sample * sample;
+            bass += self.bass_filter * self.bass_filter;
+            treble += high * high;
+        }
+
+        let len = block.len().max(1) as f32;
+        let rms = (total / len).sqrt();
+        let bass = bass / len;
+        let treble = treble / len;
+
+        let block_seconds = len / self.sample_rate;
+        self.peak = rms.max(self.peak * PEAK_DECAY_PER_SECOND.powf(block_seconds));
+
+        let beat = bass > self.bass_average * BEAT_THRESHOLD && bass > 1e-4;
+        let smoothing = (block_seconds / BEAT_HISTORY_SECONDS).min(1.0);
+        self.bass_average += smoothing * (bass - self.bass_average);
+
+        Analysis {
+            level: if self.peak > 0.0 {
+                rms / self.peak
+            } else {
+                0.0
+            },
+            brightness: if bass + treble > 0.0 {
+                treble / (bass + treble)
+            } else {
+                0.0
+            },
+            beat,
+        }
+    }
+}
+
+/// Analyze audio in real time and drive the matching lights (every light if
+/// `targets` is empty) with it until the audio runs out. Bass beats flash the
+/// lights to full, loudness sets intensity and the balance of bass and treble
+/// sets the hue.
+///
+/// Lights are updated `rate` times per second, and audio is consumed no faster
+/// than it would play so that lights keep time with a file being played
+/// alongside.
+pub(crate) async fn run(
+    source: AudioSource,
+    pcm: PcmFormat,
+    targets: Vec<String>,
+    rate: f32,
+    lights: Arc<Mutex<Vec<LightGuiState>>>,
+) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let (samples, sample_rate) = open(&source, pcm)?;
+        info!(?source, sample_rate, "audio-reactive mode started");
+
+        let block_len = ((sample_rate as f32 / rate.clamp(1.0, 50.0)) as usize).max(1);
+        let block_duration = Duration::from_secs_f32(block_len as f32 / sample_rate as f32);
+
+        let mut analyzer = Analyzer::new(sample_rate);
+        let mut samples = samples.peekable();
+        let mut block = Vec::with_capacity(block_len);
+        let mut deadline = Instant::now();
+
+        while samples.peek().is_some() {
+            block.clear();
+            block.extend(samples.by_ref().take(block_len));
+
+            let analysis = analyzer.analyze(&block);
+            apply(&analysis, &targets, &lights);
+
+            deadline += block_duration;
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        }
+
+        info!("audio input ended");
+
+        Ok(())
+    })
+    .await?
+}
+
+fn apply(analysis: &Analysis, targets: &[String], lights: &Mutex<Vec<LightGuiState>>) {
+    let intensity = if analysis.beat {
+        100.0
+    } else {
+        MIN_INTENSITY + (100.0 - MIN_INTENSITY) * analysis.level
+    };
+    let hue = BASS_HUE + (TREBLE_HUE - BASS_HUE) * analysis.brightness;
+
+    for light in lights
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|light| targets.is_empty() || targets.iter().any(|x| light.matches(x)))
+    {
+        let mut state = light.state().clone();
+        state.intensity = intensity.round() as u8;
+        state.hue = hue.round() as u8;
+        state.mode = LightMode::Hsi;
+
+        if state != *light.state() {
+            light.set_state(state);
+        }
+    }
+}
+
+type Samples = Box<dyn Iterator<Item = f32> + Send>;
+
+/// Open the source as a stream of mono samples in [-1, 1] along with its
+/// sample rate
+fn open(source: &AudioSource, pcm: PcmFormat) -> Result<(Samples, u32)> {
+    match source {
+        AudioSource::Wav(path) => {
+            let reader = WavReader::open(path)?;
+            let spec = reader.spec();
+
+            let samples: Samples = match spec.sample_format {
+                SampleFormat::Float => Box::new(reader.into_samples::<f32>().map_while(Result::ok)),
+                SampleFormat::Int => {
+                    let scale = (1u32 << (spec.bits_per_sample - 1)) as f32;
+                    Box::new(
+                        reader
+                            .into_samples::<i32>()
+                            .map_while(Result::ok)
+                            .map(move |x| x as f32 / scale),
+                    )
+                }
+            };
+
+            Ok((downmix(samples, spec.channels), spec.sample_rate))
+        }
+        AudioSource::Stdin => {
+            let mut stdin = BufReader::new(std::io::stdin());
+            let samples = std::iter::from_fn(move || {
+                let mut bytes = [0; 2];
+                stdin.read_exact(&mut bytes).ok()?;
+                Some(i16::from_le_bytes(bytes) as f32 / 32768.0)
+            });
+
+            Ok((downmix(Box::new(samples), pcm.channels), pcm.sample_rate))
+        }
+    }
+}
+
+/// Average interleaved channels down to one
+fn downmix(samples: Samples, channels: u16) -> Samples {
+    let channels = channels.max(1) as usize;
+    if channels == 1 {
+        return samples;
+    }
+
+    let mut samples = samples;
+    Box::new(std::iter::from_fn(move || {
+        let frame: Vec<f32> = samples.by_ref().take(channels).collect();
+        (frame.len() == channels).then(|| frame.iter().sum::<f32>() / channels as f32)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44100;
+
+    /// A block of a sine wave at `frequency`
+    fn sine(frequency: f32, amplitude: f32) -> Vec<f32> {
+        (0..RATE as usize / 20)
+            .map(|i| amplitude * (TAU * frequency * i as f32 / RATE as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn silence_is_dark_and_beatless() {
+        let mut analyzer = Analyzer::new(RATE);
+        let analysis = analyzer.analyze(&[0.0; 2205]);
+
+        assert_eq!(
+            analysis,
+            Analysis {
+                level: 0.0,
+                brightness: 0.0,
+                beat: false,
+            }
+        );
+        assert_eq!(analyzer.analyze(&[]).level, 0.0);
+    }
+
+    #[test]
+    fn loud_bass_after_quiet_is_a_beat() {
+        let mut analyzer = Analyzer::new(RATE);
+        // Long enough for the average to settle
+        for _ in 0..100 {
+            analyzer.analyze(&sine(60.0, 0.05));
+        }
+        assert!(!analyzer.analyze(&sine(60.0, 0.05)).beat);
+
+        let analysis = analyzer.analyze(&sine(60.0, 1.0));
+        assert!(analysis.beat);
+        assert_eq!(analysis.level, 1.0);
+
+        // Steady bass stops being a beat once it becomes the average
+        let mut analysis = analysis;
+        for _ in 0..100 {
+            analysis = analyzer.analyze(&sine(60.0, 1.0));
+        }
+        assert!(!analysis.beat);
+    }
+
+    #[test]
+    fn treble_is_brighter_than_bass() {
+        let bass = Analyzer::new(RATE).analyze(&sine(60.0, 0.5));
+        let treble = Analyzer::new(RATE).analyze(&sine(8000.0, 0.5));
+
+        assert!(bass.brightness < 0.1, "{bass:?}");
+        assert!(treble.brightness > 0.9, "{treble:?}");
+    }
+
+    #[test]
+    fn downmix_averages_frames() {
+        let samples: Samples = Box::new(vec![1.0, 0.5, -1.0, 0.0, 0.25].into_iter());
+        // The incomplete last frame is dropped
+        assert_eq!(downmix(samples, 2).collect::<Vec<_>>(), [0.75, -0.5]);
+
+        let samples: Samples = Box::new(vec![0.1, 0.2, 0.3].into_iter());
+        assert_eq!(downmix(samples, 1).collect::<Vec<_>>(), [0.1, 0.2, 0.3]);
+    }
+}
@@ -1,336 +1,405 @@
 use std::{
-    collections::HashSet,
-    fmt::Debug,
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Display,
+    pin::Pin,
+    str::FromStr,
     sync::{Arc, Mutex},
-    time::Duration,
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use crate::{
-    gui::{LightGuiState, LightMode, LightSettingsState},
-    protocol::{ColorTemperatureCommand, ModeCommand, PowerCommand},
-};
 use async_stream::stream;
 use btleplug::{
     api::{Central, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType},
-    platform::{Adapter, Manager, Peripheral},
+    platform::{Adapter, Manager, Peripheral, PeripheralId},
 };
 use eyre::{bail, eyre, Result};
-use futures::{pin_mut, stream::StreamExt, Stream};
-use tokio::{select, sync::mpsc::channel, time::sleep};
-use tokio_stream::wrappers::ReceiverStream;
-use tracing::{error, info, trace, warn};
+use futures::{
+    pin_mut,
+    stream::{self, BoxStream, StreamExt},
+    Stream,
+};
+use tokio::{
+    select,
+    time::{sleep, timeout},
+};
+use tracing::{info, warn};
 
-use crate::protocol::{HsiCommand, Packable};
+use crate::{
+    light::{Light, MacAddress},
+    protocol::WireMessage,
+    transport::LightTransport,
+};
 
 const SERVICE_UUID: uuid::Uuid = uuid::Uuid::from_bytes([
     0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x19, 0x10,
 ]);
 
+/// How long to wait between listing the peripherals an adapter has seen
+const SCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest a light gets to connect and show its services before the attempt
+/// is abandoned
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bounds on the backoff between retrying a light that failed to connect, or an
+/// adapter that failed to scan
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
 // const CMD_INIT_SESSION: &[u8] = &[
 //     0x4c, 0x54, 0x09, 0x00, 0x00, 0x53, 0x00, 0x00, 0x01, 0x00, 0x94, 0x74,
 // ];
 
-/// Useful for debugging GUI when there are no lights available to connect to -
-/// Slowly yields lights that the GUI sees as connected. Commands
-/// sent to the light are logged at INFO level.
-pub(crate) async fn scan_and_spawn_demo_mode(lights: Arc<Mutex<Vec<LightGuiState>>>) {
-    lights.lock().unwrap().push(fake_device(1));
+/// A bluetooth adapter on this machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdapterInfo {
+    /// Position in the list of adapters
+    pub index: usize,
 
-    sleep(Duration::from_secs(5)).await;
-    lights.lock().unwrap().push(fake_device(2));
+    /// e.g. `hci0`
+    pub name: String,
 
-    sleep(Duration::from_secs(10)).await;
-    lights.lock().unwrap().push(fake_device(3));
-}
-
-fn fake_device(id: u32) -> LightGuiState {
-    let (tx, rx) = channel(10);
-    let gui_state = LightGuiState::new(format!("LED {id}"), tx);
+    /// Only known on Linux
+    pub address: Option<String>,
 
-    let mut rx = debounced::debounced(ReceiverStream::new(rx), Duration::from_millis(100));
-    tokio::spawn(async move {
-        while let Some(state) = rx.next().await {
-            info!(id, ?state, "recieved state");
-        }
-    });
+    /// Everything the platform reports about the adapter
+    pub description: String,
+}
 
-    gui_state
+/// Picks a bluetooth adapter by its index, name or address
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdapterSelector {
+    Index(usize),
+    /// Name or address, ignoring case
+    Name(String),
 }
 
-/// Run a loop that continuously scans for new compatible LEDs, spawns
-/// connection managers for those lights, and adds them to the GUI.
-pub(crate) async fn scan_and_spawn(lights: Arc<Mutex<Vec<LightGuiState>>>) {
-    let device_stream = scan_forever();
-    pin_mut!(device_stream);
-
-    while let Some(led) = device_stream.next().await {
-        let led = match led {
-            Ok(x) => x,
-            Err(e) => {
-                error!(error = ?e,"error scanning for devic");
-                continue;
+impl AdapterSelector {
+    fn matches(&self, adapter: &AdapterInfo) -> bool {
+        match self {
+            Self::Index(index) => adapter.index == *index,
+            Self::Name(name) => {
+                adapter.name.eq_ignore_ascii_case(name)
+                    || adapter
+                        .address
+                        .as_ref()
+                        .is_some_and(|address| address.eq_ignore_ascii_case(name))
             }
-        };
+        }
+    }
+}
 
-        let name = match led.mac {
-            MacAddress::Unknown => String::from("New LED"),
-            MacAddress::Known(_) => format!("{:?}", led.mac),
-        };
+impl FromStr for AdapterSelector {
+    type Err = Infallible;
 
-        let (tx, rx) = channel(10);
-        let gui_state = LightGuiState::new(name, tx);
+    /// A number is an index, anything else a name or address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => Self::Index(index),
+            Err(_) => Self::Name(s.to_owned()),
+        })
+    }
+}
 
-        {
-            lights.lock().unwrap().push(gui_state);
+impl Display for AdapterSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Name(name) => write!(f, "{name}"),
         }
+    }
+}
 
-        let rx = debounced::debounced(ReceiverStream::new(rx), Duration::from_millis(100));
+/// Every bluetooth adapter on this machine
+pub async fn adapters() -> Result<Vec<AdapterInfo>> {
+    let manager = Manager::new().await?;
+    adapter_infos(&manager.adapters().await?).await
+}
 
-        tokio::spawn(led.connection(rx));
+async fn adapter_infos(adapters: &[Adapter]) -> Result<Vec<AdapterInfo>> {
+    // Addresses are a nicety, so failing to look them up isn't an error
+    let addresses = adapter_addresses().await.unwrap_or_default();
+
+    let mut infos = Vec::new();
+    for (index, adapter) in adapters.iter().enumerate() {
+        let description = adapter.adapter_info().await?;
+        let name = description
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+
+        infos.push(AdapterInfo {
+            index,
+            address: addresses.get(&name).cloned(),
+            name,
+            description,
+        });
     }
 
-    warn!("Scanning stream hung up");
+    Ok(infos)
 }
 
-/// Combination of the bluetooth peripheral and the characteristic that all
-/// commands will be written to
-struct Led {
-    peripheral: Peripheral,
-    characteristic: Characteristic,
-
-    // CoreBluetooth hides the mac address of bluetooth accessories, so the only
-    // way to get the mac address is to inspect the device properties. This is
-    // done once at connection initialization time and the full mac address is
-    // stored here.
-    mac: MacAddress,
+/// Address of each adapter by name. btleplug doesn't expose these, so they're
+/// read from BlueZ directly.
+#[cfg(target_os = "linux")]
+async fn adapter_addresses() -> Result<HashMap<String, String>> {
+    let (resource, session) = bluez_async::BluetoothSession::new().await?;
+    let connection = tokio::spawn(resource);
+    let adapters = session.get_adapters().await;
+    connection.abort();
+
+    Ok(adapters?
+        .into_iter()
+        .map(|adapter| (adapter.id.to_string(), adapter.mac_address.to_string()))
+        .collect())
 }
 
-#[derive(PartialEq)]
-enum MacAddress {
-    Known([u8; 6]),
-    Unknown,
+#[cfg(not(target_os = "linux"))]
+async fn adapter_addresses() -> Result<HashMap<String, String>> {
+    Ok(HashMap::new())
 }
 
-impl Debug for MacAddress {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Known(data) => {
-                for byte in &data[..5] {
-                    write!(f, "{byte:02x}:")?;
-                }
-                write!(f, "{:02x}", data[5])
-            }
-            Self::Unknown => write!(f, "Unknown"),
+/// The adapters picked by `selectors`, or the first adapter if there are none
+async fn select_adapters(selectors: &[AdapterSelector]) -> Result<Vec<Adapter>> {
+    let manager = Manager::new().await?;
+    let adapters = manager.adapters().await?;
+
+    if adapters.is_empty() {
+        bail!("no bluetooth adapters found");
+    }
+    if selectors.is_empty() {
+        return Ok(adapters.into_iter().take(1).collect());
+    }
+
+    let infos = adapter_infos(&adapters).await?;
+    let mut selected = Vec::new();
+    for selector in selectors {
+        let index = infos
+            .iter()
+            .position(|info| selector.matches(info))
+            .ok_or_else(|| eyre!("no bluetooth adapter matches '{selector}'"))?;
+
+        if !selected.contains(&index) {
+            selected.push(index);
         }
     }
+
+    Ok(selected.into_iter().map(|i| adapters[i].clone()).collect())
 }
 
-impl Led {
-    /// Write the given command to this light
-    async fn cmd(&self, command: impl Packable) -> Result<()> {
-        let data = command.to_wire();
-        trace!(
-            peripheral_id = %self.peripheral.id(),
-            peripheral_mac = ?self.mac,
-            raw = %format!("{data:02x?}"),
-            ?command,
-            "write"
-        );
-        self.peripheral
-            .write(&self.characteristic, &data, WriteType::WithoutResponse)
-            .await?;
+/// A never-ending stream of compatible lights, each one connected as soon as it
+/// is found. Every light is only yielded once.
+///
+/// Problems with the adapter are yielded as errors, after which scanning
+/// restarts. Lights that fail to connect are only logged, and tried again
+/// later.
+pub struct Discovery {
+    lights: BoxStream<'static, Result<Light<BtleTransport>>>,
+}
 
-        Ok(())
+impl Discovery {
+    /// Start scanning on the first bluetooth adapter
+    pub fn new() -> Self {
+        Self::on_adapters(Vec::new())
     }
 
-    async fn discover_mac(&mut self) -> Result<()> {
-        let properties = self
-            .peripheral
-            .properties()
-            .await?
-            .ok_or_else(|| eyre!("device had no properties"))?;
-
-        for (prefix, suffix) in properties.manufacturer_data {
-            if suffix.len() != 4 {
-                continue;
-            }
+    /// Start scanning on every adapter picked by `adapters` at once, or on the
+    /// first adapter if it is empty. Each light is connected through whichever
+    /// adapter finds it first, which spreads lights across adapters that each
+    /// have a limit on how many connections they can hold.
+    pub fn on_adapters(adapters: Vec<AdapterSelector>) -> Self {
+        let lights = stream! {
+            let mut backoff = Backoff::default();
+            let adapters = loop {
+                match select_adapters(&adapters).await {
+                    Ok(adapters) => break adapters,
+                    Err(e) => {
+                        yield Err(e);
+                        sleep(backoff.next()).await;
+                    }
+                }
+            };
 
-            let [prefix_low, prefix_high] = prefix.to_le_bytes();
+            let connected = Arc::new(Mutex::new(HashMap::new()));
+            let mut lights = stream::select_all(
+                adapters
+                    .into_iter()
+                    .map(|adapter| scan_forever(adapter, connected.clone()).boxed()),
+            );
 
-            let mac = [
-                prefix_low,
-                prefix_high,
-                suffix[0],
-                suffix[1],
-                suffix[2],
-                suffix[3],
-            ];
+            while let Some(light) = lights.next().await {
+                yield light;
+            }
+        };
 
-            self.mac = MacAddress::Known(mac);
-            break;
+        Self {
+            lights: lights.boxed(),
         }
+    }
+}
 
-        Ok(())
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    /// Ensure the connection is healthy and attempt to reconnect if not
-    async fn health_check(&self) {
-        if let Ok(false) = self.peripheral.is_connected().await {
-            warn!(
-                peripheral_id = %self.peripheral.id(),
-                peripheral_mac = ?self.mac,
-                "LED disconnected"
-            );
+impl Stream for Discovery {
+    type Item = Result<Light<BtleTransport>>;
 
-            loop {
-                if let Err(e) = self.peripheral.connect().await {
-                    warn!(
-                        peripheral_id = %self.peripheral.id(),
-                        peripheral_mac = ?self.mac,
-                        error=?e,
-                        "Failed to reconnect"
-                    );
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-
-                    continue;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.lights.poll_next_unpin(cx)
+    }
+}
+
+/// Scan for compatible LEDs on `adapters` (see [`Discovery::on_adapters`]) for
+/// `timeout`, connecting to every one that is found
+pub async fn discover(
+    adapters: &[AdapterSelector],
+    timeout: Duration,
+) -> Result<Vec<Light<BtleTransport>>> {
+    let mut leds = Vec::new();
+
+    let mut device_stream = Discovery::on_adapters(adapters.to_vec());
+
+    let deadline = sleep(timeout);
+    pin_mut!(deadline);
+
+    loop {
+        select! {
+            next = device_stream.next() => match next {
+                Some(Ok(led)) => leds.push(led),
+                Some(Err(e)) => {
+                    // Dropping a light leaves it connected, and unable to be
+                    // connected by anything else until the stack notices
+                    for led in leds {
+                        let _ = led.disconnect().await;
+                    }
+                    return Err(e);
                 }
+                None => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
 
-                if let Ok(true) = self.peripheral.is_connected().await {
-                    info!(
-                        peripheral_id = %self.peripheral.id(),
-                        peripheral_mac = ?self.mac,
-                        "Reconnected"
-                    );
-                    break;
+    Ok(leds)
+}
+
+/// Scan on `adapters` (see [`Discovery::on_adapters`]) until the LED with the
+/// given MAC address is connected, giving up after `timeout`
+pub async fn find(
+    mac: MacAddress,
+    adapters: &[AdapterSelector],
+    timeout: Duration,
+) -> Result<Option<Light<BtleTransport>>> {
+    let mut device_stream = Discovery::on_adapters(adapters.to_vec());
+
+    let deadline = sleep(timeout);
+    pin_mut!(deadline);
+
+    loop {
+        select! {
+            next = device_stream.next() => match next {
+                Some(led) => {
+                    let led = led?;
+                    if led.mac() == mac {
+                        return Ok(Some(led));
+                    }
+
+                    // Dropping it would leave it connected, and unable to be
+                    // connected by anything else until the stack notices
+                    let _ = led.disconnect().await;
                 }
-            }
+                None => return Ok(None),
+            },
+            _ = &mut deadline => return Ok(None),
         }
     }
+}
 
-    /// Listens forever to a stream which yields state changes for a given light and
-    /// applies those state changes.
-    async fn connection(
-        mut self,
-        state_stream: impl Stream<Item = LightSettingsState>,
-    ) -> Result<()> {
-        let mut previous_state = LightSettingsState::default();
-        write_state_no_cmp(&self, &previous_state).await?;
+/// The btleplug backed transport: the bluetooth peripheral and the
+/// characteristic that all commands will be written to
+pub struct BtleTransport {
+    peripheral: Peripheral,
+    characteristic: Characteristic,
 
-        let mut health_interval = tokio::time::interval(Duration::from_secs(1));
+    // Keeps discovery from connecting the light again for as long as this
+    // transport is alive
+    _claim: Claim,
+}
+
+impl LightTransport for BtleTransport {
+    fn id(&self) -> String {
+        self.peripheral.id().to_string()
+    }
 
-        pin_mut!(state_stream);
+    async fn write(&self, message: WireMessage) -> Result<()> {
+        self.peripheral
+            .write(&self.characteristic, &message, WriteType::WithoutResponse)
+            .await?;
+
+        Ok(())
+    }
 
+    async fn notifications(&self) -> Result<BoxStream<'static, Vec<u8>>> {
         self.peripheral.subscribe(&self.characteristic).await?;
 
-        let mut notifications = self.peripheral.notifications().await?;
+        let notifications = self.peripheral.notifications().await?;
 
-        loop {
-            select! {
-                next = state_stream.next() => {
-                    let state = match next {
-                        None => break,
-                        Some(x) => x,
-                    };
+        Ok(notifications.map(|notif| notif.value).boxed())
+    }
 
-                    write_state(&self, &state, &previous_state).await?;
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.peripheral.is_connected().await?)
+    }
 
-                    previous_state = state;
-                }
-                _ = health_interval.tick() => {
-                    if self.mac == MacAddress::Unknown {
-                        _ = self.discover_mac().await;
-                    }
-                    self.health_check().await;
-                }
-                next = notifications.next() => {
-                    if let Some(notif) = next {
-                        trace!(
-                            peripheral_id = %self.peripheral.id(),
-                            peripheral_mac = ?self.mac,
-                            ?notif.value,
-                            %notif.uuid,
-                            "notification"
-                        );
-                    }
-                }
-            }
-        }
+    async fn reconnect(&self) -> Result<()> {
+        self.peripheral.connect().await?;
 
         Ok(())
     }
-}
 
-async fn write_state_no_cmp(led: &Led, state: &LightSettingsState) -> Result<()> {
-    let cmd = if state.enabled {
-        PowerCommand::On
-    } else {
-        PowerCommand::Off
-    };
-    led.cmd(cmd).await?;
-
-    match state.mode {
-        LightMode::Hsi => {
-            led.cmd(HsiCommand::Hue(state.hue)).await?;
-            led.cmd(HsiCommand::Saturation(state.saturation)).await?;
-            led.cmd(HsiCommand::Intensity(state.intensity)).await?;
-            led.cmd(ModeCommand::Hsi).await?;
-        }
-        LightMode::Cct => {
-            led.cmd(ColorTemperatureCommand(state.temperature)).await?;
-            led.cmd(HsiCommand::Intensity(state.intensity)).await?;
-            led.cmd(ModeCommand::Cct).await?;
-        }
+    async fn disconnect(&self) -> Result<()> {
+        self.peripheral.disconnect().await?;
+
+        Ok(())
     }
 
-    Ok(())
+    async fn mac(&self) -> Result<Option<[u8; 6]>> {
+        advertised_mac(&self.peripheral).await
+    }
 }
 
-/// Given the current and previous state of an LED, write the commands required
-/// to update the LED's state to the new state.
-async fn write_state(
-    led: &Led,
-    state: &LightSettingsState,
-    previous_state: &LightSettingsState,
-) -> Result<()> {
-    if state.enabled != previous_state.enabled {
-        let cmd = if state.enabled {
-            PowerCommand::On
-        } else {
-            PowerCommand::Off
-        };
+/// The light's MAC address, which it advertises as manufacturer data. This is
+/// known before connecting, and on every adapter that sees the light.
+async fn advertised_mac(peripheral: &Peripheral) -> Result<Option<[u8; 6]>> {
+    let properties = peripheral
+        .properties()
+        .await?
+        .ok_or_else(|| eyre!("device had no properties"))?;
+
+    for (prefix, suffix) in properties.manufacturer_data {
+        if suffix.len() != 4 {
+            continue;
+        }
 
-        led.cmd(cmd).await?;
-    }
+        let [prefix_low, prefix_high] = prefix.to_le_bytes();
 
-    match state.mode {
-        LightMode::Hsi => {
-            if state.hue != previous_state.hue {
-                led.cmd(HsiCommand::Hue(state.hue)).await?;
-            }
-            if state.saturation != previous_state.saturation {
-                led.cmd(HsiCommand::Saturation(state.saturation)).await?;
-            }
-            if state.intensity != previous_state.intensity {
-                led.cmd(HsiCommand::Intensity(state.intensity)).await?;
-            }
-            if state.mode != previous_state.mode {
-                led.cmd(ModeCommand::Hsi).await?;
-            }
-        }
-        LightMode::Cct => {
-            if state.temperature != previous_state.temperature {
-                led.cmd(ColorTemperatureCommand(state.temperature)).await?;
-            }
-            if state.intensity != previous_state.intensity {
-                led.cmd(HsiCommand::Intensity(state.intensity)).await?;
-            }
-            if state.mode != previous_state.mode {
-                led.cmd(ModeCommand::Cct).await?;
-            }
-        }
+        return Ok(Some([
+            prefix_low,
+            prefix_high,
+            suffix[0],
+            suffix[1],
+            suffix[2],
+            suffix[3],
+        ]));
     }
 
-    Ok(())
+    Ok(None)
 }
 
 /// Find the BTLE characteristic for controlling the GVM LED
@@ -340,7 +409,7 @@ async fn find_characteristic(led: &Peripheral) -> Result<Characteristic> {
     for service in led.services() {
         if service.uuid == SERVICE_UUID {
             // TODO - use UUID here
-            let characteristic = service.characteristics.into_iter().nth(0);
+            let characteristic = service.characteristics.into_iter().next();
             return characteristic.ok_or_else(|| eyre!("service didn't have characteristic"));
         }
     }
@@ -354,9 +423,11 @@ async fn find_leds(central: &Adapter) -> Result<Vec<Peripheral>> {
     let mut peripherals = Vec::new();
 
     for p in central.peripherals().await? {
-        let local_name = match p.properties().await? {
-            Some(x) => x.local_name,
-            None => continue,
+        // A peripheral that has just gone away can't be asked for properties,
+        // which is no reason to give up on the rest
+        let local_name = match p.properties().await {
+            Ok(Some(x)) => x.local_name,
+            Ok(None) | Err(_) => continue,
         };
 
         if let Some(name) = local_name {
@@ -369,46 +440,176 @@ async fn find_leds(central: &Adapter) -> Result<Vec<Peripheral>> {
     Ok(peripherals)
 }
 
-/// Infinite loop scanning for compatible LEDs
-fn scan_forever() -> impl Stream<Item = Result<Led>> {
-    stream! {
-        let manager = Manager::new().await?;
+/// Exponential backoff between retries, from `RETRY_MIN` doubling up to
+/// `RETRY_MAX`
+#[derive(Debug, Default)]
+struct Backoff {
+    failures: u32,
+}
 
-        let adapters = manager.adapters().await?;
-        let central = adapters.into_iter().nth(0).unwrap();
+impl Backoff {
+    /// How long to wait before the next retry
+    fn next(&mut self) -> Duration {
+        let delay = RETRY_MIN
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(RETRY_MAX);
+        self.failures += 1;
 
-        let mut connected = HashSet::new();
+        delay
+    }
+}
 
-        central
-            .start_scan(ScanFilter::default())
-            .await?;
+/// Connect to a peripheral and find the characteristic that controls it, giving
+/// up after `CONNECT_TIMEOUT`
+async fn connect(peripheral: &Peripheral) -> Result<Characteristic> {
+    let setup = async {
+        peripheral.connect().await?;
+        find_characteristic(peripheral).await
+    };
+
+    let result = match timeout(CONNECT_TIMEOUT, setup).await {
+        Ok(result) => result,
+        Err(_) => Err(eyre!("timed out after {CONNECT_TIMEOUT:?}")),
+    };
+
+    if result.is_err() {
+        // Don't leave a half set up connection holding one of the adapter's
+        // connection slots
+        let _ = peripheral.disconnect().await;
+    }
+
+    result
+}
+
+/// What a light is known by while deciding whether it is already connected.
+/// Peripheral ids are only unique to one adapter, and CoreBluetooth hides
+/// addresses altogether, so the advertised MAC address is used wherever the
+/// light sends one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LightKey {
+    Mac([u8; 6]),
+    Peripheral(PeripheralId),
+}
+
+/// Every light discovery has connected, shared between adapters. A light maps
+/// to `None` while it is connected, or to when it may be connected again once
+/// it has been dropped.
+type Claims = Arc<Mutex<HashMap<LightKey, Option<Instant>>>>;
+
+/// A connected light's entry in [`Claims`], handed back when the light is
+/// dropped - because its connection manager ended, or because it was
+/// disconnected straight away. It is then left alone for `RETRY_MAX`, so that
+/// a light nobody wants isn't connected over and over.
+struct Claim {
+    key: LightKey,
+    claims: Claims,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let retry_at = Instant::now() + RETRY_MAX;
+        self.claims
+            .lock()
+            .unwrap()
+            .insert(self.key.clone(), Some(retry_at));
+    }
+}
+
+/// Infinite loop scanning for compatible LEDs on one adapter. `connected` is
+/// shared between adapters, so that a light seen by several is only connected
+/// once.
+///
+/// A light that fails to connect is tried again after a backoff, and the scan
+/// itself is restarted if the adapter fails.
+fn scan_forever(
+    central: Adapter,
+    connected: Claims,
+) -> impl Stream<Item = Result<Light<BtleTransport>>> {
+    stream! {
+        let mut scan_backoff = Backoff::default();
+        let mut retries: HashMap<PeripheralId, (Backoff, Instant)> = HashMap::new();
 
         loop {
-            let leds = find_leds(&central).await?;
+            if let Err(e) = central.start_scan(ScanFilter::default()).await {
+                yield Err(eyre::Report::new(e).wrap_err("failed to start scanning"));
+                sleep(scan_backoff.next()).await;
+                continue;
+            }
 
-            for peripheral in leds {
-                if !connected.insert(peripheral.id()) {
-                    continue;
-                }
+            loop {
+                let leds = match find_leds(&central).await {
+                    Ok(leds) => leds,
+                    Err(e) => {
+                        yield Err(e.wrap_err("failed to list peripherals"));
+                        break;
+                    }
+                };
+                scan_backoff = Backoff::default();
 
-                peripheral.connect().await?;
+                for peripheral in leds {
+                    let id = peripheral.id();
 
-                let characteristic = find_characteristic(&peripheral).await?;
+                    if retries.get(&id).is_some_and(|(_, at)| *at > Instant::now()) {
+                        continue;
+                    }
 
-                let mut led = Led{ peripheral, characteristic, mac: MacAddress::Unknown };
+                    let key = match advertised_mac(&peripheral).await {
+                        Ok(Some(mac)) => LightKey::Mac(mac),
+                        Ok(None) | Err(_) => LightKey::Peripheral(id.clone()),
+                    };
 
-                _ = led.discover_mac().await;
+                    // Claimed while connecting so that other adapters leave it
+                    // alone, and released again if connecting fails
+                    {
+                        let mut connected = connected.lock().unwrap();
+                        match connected.get(&key) {
+                            Some(None) => continue,
+                            Some(Some(at)) if *at > Instant::now() => continue,
+                            _ => connected.insert(key.clone(), None),
+                        };
+                    }
 
-                info!(
-                    peripheral_id = %led.peripheral.id(),
-                    peripheral_mac = ?led.mac,
-                    "connected"
-                );
+                    match connect(&peripheral).await {
+                        Ok(characteristic) => {
+                            retries.remove(&id);
+
+                            let led = Light::new(BtleTransport {
+                                peripheral,
+                                characteristic,
+                                _claim: Claim {
+                                    key,
+                                    claims: connected.clone(),
+                                },
+                            })
+                            .await;
+
+                            info!(
+                                peripheral_id = %led.transport().id(),
+                                peripheral_mac = ?led.mac(),
+                                "connected"
+                            );
+
+                            yield Ok(led)
+                        }
+                        Err(e) => {
+                            connected.lock().unwrap().remove(&key);
+
+                            let (backoff, at) = retries
+                                .entry(id.clone())
+                                .or_insert_with(|| (Backoff::default(), Instant::now()));
+                            let delay = backoff.next();
+                            *at = Instant::now() + delay;
+
+                            warn!(peripheral_id = %id, error = ?e, retry_in = ?delay, "failed to connect");
+                        }
+                    }
+                }
 
-                yield Ok(led)
+                sleep(SCAN_INTERVAL).await;
             }
 
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            let _ = central.stop_scan().await;
+            sleep(scan_backoff.next()).await;
         }
     }
 }
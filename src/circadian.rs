@@ -0,0 +1,154 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{Local, NaiveTime, Timelike};
+use gvm::{LightMode, LightState};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{config::Config, gui::LightGuiState};
+
+/// Range of color temperatures the lights support, in Kelvin
+const MIN_KELVIN: f32 = 3200.0;
+const MAX_KELVIN: f32 = 5600.0;
+
+/// Each update is faded in over this long so that turning circadian mode on
+/// doesn't jump straight to a very different color
+const FADE: Duration = Duration::from_secs(2);
+
+const SECONDS_PER_DAY: f32 = 24.0 * 60.0 * 60.0;
+
+/// How lights in circadian mode change over the day
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Circadian {
+    /// Seconds between updates
+    pub interval: f32,
+
+    /// Color temperature in Kelvin through the day, clamped to 3200-5600K
+    pub temperature: Vec<CurvePoint>,
+
+    /// Intensity through the day - Range: [0, 100]. Intensity is left alone if
+    /// there are no points.
+    pub intensity: Vec<CurvePoint>,
+}
+
+/// A value at a time of day. Values in between points are blended linearly,
+/// wrapping around from the last point of the day to the first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CurvePoint {
+    pub at: NaiveTime,
+    pub value: f32,
+}
+
+impl Default for Circadian {
+    fn default() -> Self {
+        let point = |hour, value| CurvePoint {
+            at: NaiveTime::from_hms_opt(hour, 0, 0).unwrap(),
+            value,
+        };
+
+        Self {
+            interval: 60.0,
+            temperature: vec![
+                point(6, 3200.0),
+                point(9, 5000.0),
+                point(13, 5600.0),
+                point(17, 5000.0),
+                point(20, 3200.0),
+            ],
+            intensity: Vec::new(),
+        }
+    }
+}
+
+impl Circadian {
+    /// The state a light following the curves should be in at `time`, given
+    /// the state it is in now
+    pub fn state_at(&self, state: &LightState, time: NaiveTime) -> LightState {
+        let mut state = state.clone();
+        state.mode = LightMode::Cct;
+
+        if let Some(kelvin) = curve_at(&self.temperature, time) {
+            state.temperature = (kelvin.clamp(MIN_KELVIN, MAX_KELVIN) / 100.0).round() as u8;
+        }
+        if let Some(intensity) = curve_at(&self.intensity, time) {
+            state.intensity = intensity.clamp(0.0, 100.0).round() as u8;
+        }
+
+        state
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::try_from_secs_f32(self.interval.max(1.0)).unwrap_or(Duration::from_secs(60))
+    }
+
+    /// Move a light in circadian mode to where it should be now. Lights which
+    /// are off or not in circadian mode are left alone.
+    pub fn update(&self, light: &mut LightGuiState) {
+        if !light.circadian() || !light.state().enabled {
+            return;
+        }
+
+        let state = self.state_at(light.state(), Local::now().time());
+        if state != *light.state() {
+            light.fade_to_automatically(state, FADE);
+        }
+    }
+}
+
+/// The value of a curve at `time`, or `None` if it has no points
+fn curve_at(points: &[CurvePoint], time: NaiveTime) -> Option<f32> {
+    let mut points = points.to_vec();
+    points.sort_by_key(|point| point.at);
+
+    let seconds = |time: NaiveTime| time.num_seconds_from_midnight() as f32;
+    let now = seconds(time);
+
+    let (from, to) = match points.iter().position(|point| seconds(point.at) > now) {
+        Some(0) | None => (points.last()?, points.first()?),
+        Some(next) => (&points[next - 1], &points[next]),
+    };
+
+    let mut span = seconds(to.at) - seconds(from.at);
+    if span <= 0.0 {
+        span += SECONDS_PER_DAY;
+    }
+    let elapsed = (now - seconds(from.at)).rem_euclid(SECONDS_PER_DAY);
+
+    Some(from.value + (to.value - from.value) * (elapsed / span))
+}
+
+/// Save which lights follow the curves if that has changed, whatever changed
+/// it
+pub(crate) fn save_modes(config: &Mutex<Config>, lights: &[LightGuiState]) {
+    let mut config = config.lock().unwrap();
+    if config.record_circadian(lights) {
+        let saved = config.update(|config| {
+            config.record_circadian(lights);
+        });
+        if let Err(e) = saved {
+            error!(error = ?e, "failed to save config");
+        }
+    }
+}
+
+/// Keep lights in circadian mode following the config's curves forever
+pub(crate) async fn run(config: Arc<Mutex<Config>>, lights: Arc<Mutex<Vec<LightGuiState>>>) {
+    loop {
+        let circadian = config.lock().unwrap().circadian.clone();
+
+        {
+            let mut lights = lights.lock().unwrap();
+            // Catches lights taken off the curves by the HTTP API, OSC and others
+            save_modes(&config, &lights);
+            for light in lights.iter_mut() {
+                circadian.update(light);
+            }
+        }
+
+        tokio::time::sleep(circadian.interval()).await;
+    }
+}
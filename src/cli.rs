@@ -0,0 +1,621 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use clap::{Args, Subcommand, ValueEnum};
+use eyre::{bail, eyre, Result};
+use gvm::{
+    bluetooth,
+    protocol::{
+        ColorTemperatureCommand, Command, HsiCommand, ModeCommand, PowerCommand, Scene,
+        SceneCommand, HUE_RANGE,
+    },
+    simulator::SimulatorTransport,
+    transport::LightTransport,
+    AdapterSelector, Light, MacAddress,
+};
+
+use crate::{
+    config::Config,
+    daemon::{self, Request, Response},
+    effects::Effect,
+    show::Show,
+};
+
+/// Exit code used when the requested light, group or cue could not be found
+const EXIT_NOT_FOUND: u8 = 2;
+
+/// Control lights from the command line without starting the GUI
+#[derive(Subcommand, Debug)]
+pub(crate) enum CliCommand {
+    /// Print the MAC address of every light in range
+    List {
+        /// Seconds to scan for
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+    },
+
+    /// Print the index, name and address of every bluetooth adapter, for use
+    /// with `--adapter`
+    ListAdapters,
+
+    /// Set any combination of mode, color and intensity on a light
+    Set {
+        #[command(flatten)]
+        target: Target,
+
+        #[arg(long)]
+        mode: Option<Mode>,
+
+        /// Color temperature, used in CCT mode - range [3200, 5600]
+        #[arg(long, value_parser = clap::value_parser!(u16).range(3200..=5600))]
+        kelvin: Option<u16>,
+
+        /// Range [0, 82]
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..HUE_RANGE as i64))]
+        hue: Option<u8>,
+
+        /// Percent
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        sat: Option<u8>,
+
+        /// Percent
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        intensity: Option<u8>,
+    },
+
+    /// Turn a light on or off
+    Power {
+        #[command(flatten)]
+        target: Target,
+
+        state: PowerState,
+    },
+
+    /// Put a light into HSI mode with the given color
+    Hsi {
+        #[command(flatten)]
+        target: Target,
+
+        /// Range [0, 82]
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..HUE_RANGE as i64))]
+        hue: u8,
+
+        /// Percent
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        sat: u8,
+
+        /// Percent
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        intensity: u8,
+    },
+
+    /// Play one of the light's built-in scenes
+    Scene {
+        #[command(flatten)]
+        target: Target,
+
+        /// lightning, cop-car, candle, tv, bad-bulb, party, disco or paparazzi
+        name: Scene,
+
+        /// Seconds between transitions - range [0.1, 5]
+        #[arg(long)]
+        interval: Option<f32>,
+
+        /// Percent
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        intensity: Option<u8>,
+    },
+
+    /// List or recall presets saved from the GUI
+    Preset {
+        #[command(subcommand)]
+        action: PresetAction,
+    },
+
+    /// Run a host effect on a light or group through a running daemon
+    Effect {
+        /// MAC address of the light, or the name of a group
+        #[arg(long)]
+        light: String,
+
+        /// breathe, strobe, candle, rainbow, police-chase, lightning, or stop
+        name: String,
+
+        /// Cycles per second
+        #[arg(long, default_value_t = 0.5)]
+        speed: f32,
+
+        /// How far the effect pulls the light from its own state, in percent
+        #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=100))]
+        depth: u8,
+
+        /// Color to run the effect in - range [0, 82]
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..HUE_RANGE as i64))]
+        hue: Option<u8>,
+    },
+
+    /// Run the show's cue list on a running daemon
+    Cue {
+        #[command(subcommand)]
+        action: CueAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum CueAction {
+    /// Print every cue in the show, marking the current one
+    List,
+
+    /// Start the next cue
+    Go,
+
+    /// Return to the previous cue
+    Back,
+
+    /// Start a cue straight away
+    Jump {
+        /// Cue number (counting from 1) or name
+        cue: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum PresetAction {
+    /// Print the name of every preset and the lights it covers
+    List,
+
+    /// Set every light in the preset to its stored state
+    Recall {
+        name: String,
+
+        /// Seconds to spend looking for the preset's lights
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Target {
+    /// MAC address of the light to control, e.g. `a4:c1:38:00:11:22`, or the
+    /// name of a group of lights from the config
+    #[arg(long)]
+    light: String,
+
+    /// Seconds to spend looking for the light before giving up
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+
+    /// Seconds to fade to the new state over. Only possible through a running
+    /// daemon, which knows the state the light is fading from.
+    #[arg(long, default_value_t = 0.0)]
+    fade: f32,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub(crate) enum Mode {
+    Cct,
+    Hsi,
+    Scene,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub(crate) enum PowerState {
+    On,
+    Off,
+}
+
+/// Run a single command to completion. If a daemon is running the command is
+/// sent to it, otherwise lights are only connected for as long as it takes to
+/// apply the command.
+pub(crate) async fn run(
+    command: CliCommand,
+    demo: bool,
+    socket: Option<PathBuf>,
+    adapters: Vec<AdapterSelector>,
+    config: Config,
+    show_path: PathBuf,
+) -> Result<ExitCode> {
+    let daemon_socket = daemon::find_socket(socket);
+
+    let (target, commands) = match command {
+        CliCommand::List { timeout } => {
+            match daemon_socket {
+                Some(path) => list_from_daemon(&path).await?,
+                None => list(&adapters, Duration::from_secs(timeout), demo).await?,
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        CliCommand::ListAdapters => return list_adapters(demo).await,
+        CliCommand::Preset { action } => {
+            return preset(action, &config, daemon_socket.as_deref(), &adapters, demo).await;
+        }
+        CliCommand::Cue { action } => {
+            return cue(action, &show_path, daemon_socket.as_deref()).await;
+        }
+        CliCommand::Effect {
+            light,
+            name,
+            speed,
+            depth,
+            hue,
+        } => {
+            let effect = match name.as_str() {
+                "stop" => None,
+                name => Some(Effect {
+                    kind: name.parse().map_err(|e: String| eyre!(e))?,
+                    speed,
+                    depth,
+                    hue,
+                }),
+            };
+
+            let Some(socket) = daemon_socket else {
+                bail!("effects need a running daemon, start one with `gvm-led-control daemon`");
+            };
+
+            return request_named(&socket, &Request::Effect { light, effect }).await;
+        }
+        CliCommand::Set {
+            target,
+            mode,
+            kelvin,
+            hue,
+            sat,
+            intensity,
+        } => {
+            let mut commands = Vec::new();
+            if let Some(kelvin) = kelvin {
+                commands.push(Command::ColorTemperature(ColorTemperatureCommand(
+                    (kelvin / 100) as u8,
+                )));
+            }
+            if let Some(hue) = hue {
+                commands.push(Command::Hsi(HsiCommand::Hue(hue)));
+            }
+            if let Some(sat) = sat {
+                commands.push(Command::Hsi(HsiCommand::Saturation(sat)));
+            }
+            if let Some(intensity) = intensity {
+                commands.push(Command::Hsi(HsiCommand::Intensity(intensity)));
+            }
+            if let Some(mode) = mode {
+                commands.push(Command::Mode(match mode {
+                    Mode::Cct => ModeCommand::Cct,
+                    Mode::Hsi => ModeCommand::Hsi,
+                    Mode::Scene => ModeCommand::Scene,
+                }));
+            }
+
+            if commands.is_empty() {
+                bail!("nothing to set, pass at least one of --mode, --kelvin, --hue, --sat or --intensity");
+            }
+
+            (target, commands)
+        }
+        CliCommand::Power { target, state } => {
+            let cmd = match state {
+                PowerState::On => PowerCommand::On,
+                PowerState::Off => PowerCommand::Off,
+            };
+
+            (target, vec![Command::Power(cmd)])
+        }
+        CliCommand::Hsi {
+            target,
+            hue,
+            sat,
+            intensity,
+        } => (
+            target,
+            vec![
+                Command::Hsi(HsiCommand::Hue(hue)),
+                Command::Hsi(HsiCommand::Saturation(sat)),
+                Command::Hsi(HsiCommand::Intensity(intensity)),
+                Command::Mode(ModeCommand::Hsi),
+            ],
+        ),
+        CliCommand::Scene {
+            target,
+            name,
+            interval,
+            intensity,
+        } => {
+            let mut commands = vec![Command::Scene(SceneCommand::Pick(name))];
+            if let Some(interval) = interval {
+                if !(0.1..=5.0).contains(&interval) {
+                    bail!("--interval must be between 0.1 and 5 seconds");
+                }
+                commands.push(Command::Scene(SceneCommand::Interval(
+                    (interval * 10.0).round() as u8,
+                )));
+            }
+            if let Some(intensity) = intensity {
+                commands.push(Command::Hsi(HsiCommand::Intensity(intensity)));
+            }
+            commands.push(Command::Mode(ModeCommand::Scene));
+
+            (target, commands)
+        }
+    };
+
+    let ids = match config.group_members(&target.light) {
+        members if members.is_empty() => vec![target.light],
+        members => members,
+    };
+
+    if let Some(path) = daemon_socket {
+        let mut missing = false;
+        for id in &ids {
+            missing |= !apply_through_daemon(&path, id, &commands, target.fade).await?;
+        }
+
+        return Ok(if missing {
+            ExitCode::from(EXIT_NOT_FOUND)
+        } else {
+            ExitCode::SUCCESS
+        });
+    }
+
+    if target.fade > 0.0 {
+        eprintln!("no daemon is running, --fade is ignored");
+    }
+
+    let macs = ids
+        .iter()
+        .map(|id| id.parse())
+        .collect::<Result<Vec<MacAddress>>>()?;
+
+    if demo {
+        for mac in macs {
+            let MacAddress::Known(bytes) = mac else {
+                unreachable!("parsed MAC addresses are always known");
+            };
+            let led = Light::new(SimulatorTransport::new("simulated", Some(bytes))).await;
+            apply(&led, &commands).await?;
+            println!("{:?}", led.transport().light());
+        }
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let timeout = Duration::from_secs(target.timeout);
+
+    if let [mac] = macs.as_slice() {
+        return match bluetooth::find(*mac, &adapters, timeout).await? {
+            Some(led) => {
+                apply(&led, &commands).await?;
+                Ok(ExitCode::SUCCESS)
+            }
+            None => {
+                eprintln!("light {mac:?} was not found");
+                Ok(ExitCode::from(EXIT_NOT_FOUND))
+            }
+        };
+    }
+
+    // A group has to wait out the whole timeout, since there's no telling
+    // when the last member has been found
+    let mut remaining = macs.len();
+    for led in bluetooth::discover(&adapters, timeout).await? {
+        if macs.contains(&led.mac()) {
+            apply(&led, &commands).await?;
+            remaining -= 1;
+        }
+    }
+
+    if remaining > 0 {
+        eprintln!("{remaining} light(s) in the group were not found");
+        return Ok(ExitCode::from(EXIT_NOT_FOUND));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn list_from_daemon(socket: &Path) -> Result<()> {
+    let Response::Lights { lights } = daemon::request(socket, &Request::List).await? else {
+        bail!("unexpected response from daemon");
+    };
+
+    for light in lights {
+        println!("{}\t{}", light.id, light.name);
+    }
+
+    Ok(())
+}
+
+/// Apply commands to the state the daemon holds for a light, letting the daemon
+/// work out what needs to be written. Returns false if the daemon doesn't know
+/// the light.
+async fn apply_through_daemon(
+    socket: &Path,
+    light: &str,
+    commands: &[Command],
+    fade: f32,
+) -> Result<bool> {
+    let get = Request::Get {
+        light: light.to_owned(),
+    };
+    let mut state = match daemon::request(socket, &get).await {
+        Ok(Response::Light { light }) => light.state,
+        Ok(_) => bail!("unexpected response from daemon"),
+        Err(e) if e.is::<daemon::NotFound>() => {
+            eprintln!("{e}");
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    };
+
+    for command in commands {
+        state.apply(command);
+    }
+
+    let set = Request::Set {
+        light: light.to_owned(),
+        state,
+        fade,
+    };
+    daemon::request(socket, &set).await?;
+
+    Ok(true)
+}
+
+async fn preset(
+    action: PresetAction,
+    config: &Config,
+    daemon_socket: Option<&Path>,
+    adapters: &[AdapterSelector],
+    demo: bool,
+) -> Result<ExitCode> {
+    let (name, timeout) = match action {
+        PresetAction::List => {
+            for (name, preset) in &config.presets {
+                let lights: Vec<_> = preset.lights.keys().map(String::as_str).collect();
+                println!("{name}\t{}", lights.join(", "));
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        PresetAction::Recall { name, timeout } => (name, timeout),
+    };
+
+    let Some(preset) = config.presets.get(&name) else {
+        eprintln!("no preset named '{name}'");
+        return Ok(ExitCode::from(EXIT_NOT_FOUND));
+    };
+
+    if let Some(socket) = daemon_socket {
+        // The daemon only sends what differs from each light's current state
+        for (light, state) in &preset.lights {
+            let set = Request::Set {
+                light: light.clone(),
+                state: state.clone(),
+                fade: preset.fade,
+            };
+            daemon::request(socket, &set).await?;
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if demo {
+        for state in preset.lights.values() {
+            let mut led = Light::new(SimulatorTransport::new("simulated", None)).await;
+            led.set_state(state).await?;
+            println!("{:?}", led.transport().light());
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut remaining = preset.lights.len();
+    for mut led in bluetooth::discover(adapters, Duration::from_secs(timeout)).await? {
+        if let Some(state) = preset.lights.get(&led.id()) {
+            led.set_state(state).await?;
+            remaining -= 1;
+        }
+    }
+
+    if remaining > 0 {
+        eprintln!("{remaining} light(s) in preset '{name}' were not found");
+        return Ok(ExitCode::from(EXIT_NOT_FOUND));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn cue(
+    action: CueAction,
+    show_path: &Path,
+    daemon_socket: Option<&Path>,
+) -> Result<ExitCode> {
+    let request = match action {
+        CueAction::List => {
+            let (cues, current) = match daemon_socket {
+                Some(socket) => {
+                    let Response::Cues { cues, current } =
+                        daemon::request(socket, &Request::Cues).await?
+                    else {
+                        bail!("unexpected response from daemon");
+                    };
+                    (cues, current)
+                }
+                None => {
+                    let show = Show::load(show_path)?;
+                    (show.cues.into_iter().map(|cue| cue.name).collect(), None)
+                }
+            };
+
+            for (index, name) in cues.iter().enumerate() {
+                let marker = if current == Some(index) { ">" } else { " " };
+                println!("{marker} {}\t{name}", index + 1);
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        CueAction::Go => Request::Go,
+        CueAction::Back => Request::Back,
+        CueAction::Jump { cue } => Request::Jump { cue },
+    };
+
+    let Some(socket) = daemon_socket else {
+        bail!("running cues needs a running daemon, start one with `gvm-led-control daemon`");
+    };
+
+    request_named(socket, &request).await
+}
+
+/// Send a request naming a light, group or cue to the daemon. Exits with
+/// `EXIT_NOT_FOUND` if the daemon doesn't know the name, and fails on any other
+/// problem.
+async fn request_named(socket: &Path, request: &Request) -> Result<ExitCode> {
+    match daemon::request(socket, request).await {
+        Ok(_) => Ok(ExitCode::SUCCESS),
+        Err(e) if e.is::<daemon::NotFound>() => {
+            eprintln!("{e}");
+            Ok(ExitCode::from(EXIT_NOT_FOUND))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn list(adapters: &[AdapterSelector], timeout: Duration, demo: bool) -> Result<()> {
+    if demo {
+        for id in 1..=3 {
+            println!("5e:00:00:00:00:{id:02x}\tsimulated-{id}");
+        }
+        return Ok(());
+    }
+
+    for led in bluetooth::discover(adapters, timeout).await? {
+        println!("{:?}\t{}", led.mac(), led.transport().id());
+    }
+
+    Ok(())
+}
+
+async fn list_adapters(demo: bool) -> Result<ExitCode> {
+    if demo {
+        println!("0\tsimulated\t-");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let adapters = bluetooth::adapters().await?;
+    if adapters.is_empty() {
+        eprintln!("no bluetooth adapters found");
+        return Ok(ExitCode::from(EXIT_NOT_FOUND));
+    }
+
+    for adapter in adapters {
+        let address = adapter.address.as_deref().unwrap_or("-");
+        println!("{}\t{}\t{address}", adapter.index, adapter.name);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn apply(led: &Light<impl LightTransport>, commands: &[Command]) -> Result<()> {
+    for command in commands {
+        led.cmd(command.clone()).await?;
+    }
+
+    Ok(())
+}
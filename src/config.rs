@@ -0,0 +1,521 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use eyre::{eyre, Result};
+use gvm::LightState;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    circadian::Circadian,
+    gui::LightGuiState,
+    schedule::{Location, Rule},
+};
+
+/// Settings which persist between runs, stored as TOML
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Per-light settings keyed by light id (the MAC address where known)
+    pub lights: BTreeMap<String, LightConfig>,
+
+    /// Named looks which can be recalled from the GUI or command line
+    pub presets: BTreeMap<String, Preset>,
+
+    /// Where the lights are, needed for sunrise and sunset rules
+    pub location: Option<Location>,
+
+    /// Rules which change lights at set times of day
+    pub schedule: Vec<Rule>,
+
+    /// Daily color temperature and intensity curves for lights in circadian
+    /// mode
+    pub circadian: Circadian,
+
+    /// Intermediate states sent to each light per second while fading or
+    /// running an effect. Lower this if lights struggle to keep up.
+    pub fade_rate: Option<f32>,
+
+    /// Where this config was loaded from and will be saved to
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LightConfig {
+    /// Display name, replacing the MAC address in the GUI
+    pub name: Option<String>,
+
+    /// State written to the light when it first connects
+    pub default_state: Option<LightState>,
+
+    /// Groups this light belongs to, which can be controlled together from
+    /// the GUI and addressed by name from external controllers
+    pub groups: Vec<String>,
+
+    /// When false, the light is disconnected as soon as it is found
+    pub auto_connect: bool,
+
+    /// Whether the light starts out following the circadian curves
+    pub circadian: bool,
+}
+
+impl Default for LightConfig {
+    fn default() -> Self {
+        Self {
+            name: None,
+            default_state: None,
+            groups: Vec::new(),
+            auto_connect: true,
+            circadian: false,
+        }
+    }
+}
+
+/// A snapshot of the state of one or more lights
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Preset {
+    /// Light id to the state that light should be in
+    pub lights: BTreeMap<String, LightState>,
+
+    /// Seconds to fade from the current state to the preset over
+    pub fade: f32,
+}
+
+impl Preset {
+    /// Snapshot the given lights
+    pub fn capture<'a>(lights: impl IntoIterator<Item = &'a LightGuiState>) -> Self {
+        Self {
+            lights: lights
+                .into_iter()
+                .map(|light| (light.id().to_owned(), light.state().clone()))
+                .collect(),
+            fade: 0.0,
+        }
+    }
+
+    /// Send the stored states to any of the lights that are part of this
+    /// preset. Lights which aren't connected are skipped.
+    pub fn recall(&self, lights: &mut [LightGuiState]) {
+        for light in lights {
+            if let Some(state) = self.lights.get(light.id()) {
+                light.fade_to(state.clone(), self.fade_duration());
+            }
+        }
+    }
+
+    pub fn fade_duration(&self) -> Duration {
+        Duration::try_from_secs_f32(self.fade).unwrap_or_default()
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/gvm-led-control/config.toml` or the platform
+    /// equivalent
+    pub fn default_path() -> Result<PathBuf> {
+        let dir = dirs::config_dir().ok_or_else(|| eyre!("no config directory on this system"))?;
+
+        Ok(dir.join("gvm-led-control").join("config.toml"))
+    }
+
+    /// Load the config at `path`, or an empty config if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config: Config = match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e.into()),
+        };
+        config.path = path.to_owned();
+
+        Ok(config)
+    }
+
+    /// Write the config back to where it was loaded from
+    fn save(&self) -> Result<()> {
+        write_atomically(&self.path, &toml::to_string_pretty(self)?)
+    }
+
+    /// Make a change and save it. The GUI and a daemon can share a config, so
+    /// the change is made to what is in the file now rather than to this copy,
+    /// which would lose whatever the other one saved since it was loaded.
+    pub fn update(&mut self, change: impl FnOnce(&mut Config)) -> Result<()> {
+        let mut latest = Config::load(&self.path)?;
+        change(&mut latest);
+        latest.save()?;
+        *self = latest;
+
+        Ok(())
+    }
+
+    pub fn light(&self, id: &str) -> Option<&LightConfig> {
+        self.lights.get(id)
+    }
+
+    /// Settings for a light, creating them if this light has never been
+    /// configured
+    pub fn light_mut(&mut self, id: &str) -> &mut LightConfig {
+        self.lights.entry(id.to_owned()).or_default()
+    }
+
+    /// Remember which lights follow the circadian curves, so that a light taken
+    /// off them by hand stays off after a restart. Returns true if anything
+    /// changed.
+    pub fn record_circadian<'a>(
+        &mut self,
+        lights: impl IntoIterator<Item = &'a LightGuiState>,
+    ) -> bool {
+        let mut changed = false;
+        for light in lights {
+            let saved = self.light(light.id()).is_some_and(|x| x.circadian);
+            if saved != light.circadian() {
+                self.light_mut(light.id()).circadian = light.circadian();
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Every group that at least one light belongs to
+    pub fn groups(&self) -> BTreeSet<String> {
+        self.lights
+            .values()
+            .flat_map(|light| light.groups.iter().cloned())
+            .collect()
+    }
+
+    /// Ids of the lights in a group
+    pub fn group_members(&self, group: &str) -> Vec<String> {
+        self.lights
+            .iter()
+            .filter(|(_, light)| light.groups.iter().any(|name| name == group))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
+
+/// How often changes to the last states are written out. Lights can be driven
+/// many times a second by DMX, audio or a dragged slider, so changes are
+/// batched up rather than each one rewriting the file.
+const LAST_STATES_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The last state applied to each light, so that lights come back the way they
+/// were left rather than in the firmware's default state. Kept in its own file
+/// next to the config since it changes far more often.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct LastStates {
+    lights: BTreeMap<String, LightState>,
+
+    #[serde(skip)]
+    path: PathBuf,
+
+    /// Whether anything has changed since the file was last written
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl LastStates {
+    /// Load the last states stored beside the config at `config_path`
+    pub fn load(config_path: &Path) -> Result<Self> {
+        let path = config_path.with_file_name("last-state.toml");
+
+        let mut states: LastStates = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => LastStates::default(),
+            Err(e) => return Err(e.into()),
+        };
+        states.path = path;
+
+        Ok(states)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&LightState> {
+        self.lights.get(id)
+    }
+
+    /// Remember the state applied to a light, to be written out by the next
+    /// save
+    pub fn record(&mut self, id: &str, state: &LightState) {
+        if self.lights.get(id) != Some(state) {
+            self.lights.insert(id.to_owned(), state.clone());
+            self.dirty = true;
+        }
+    }
+
+    /// The path and contents to write, if anything has changed since the last
+    /// call
+    fn take_changes(&mut self) -> Result<Option<(PathBuf, String)>> {
+        if !self.dirty {
+            return Ok(None);
+        }
+
+        let contents = toml::to_string_pretty(self)?;
+        self.dirty = false;
+
+        Ok(Some((self.path.clone(), contents)))
+    }
+
+    /// Write out any changes straight away
+    pub fn save(&mut self) -> Result<()> {
+        match self.take_changes()? {
+            Some((path, contents)) => write_atomically(&path, &contents),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write out changes to the last states every few seconds forever, keeping the
+/// file IO off the async worker threads
+pub(crate) async fn save_last_states(states: Arc<Mutex<LastStates>>) {
+    let mut interval = tokio::time::interval(LAST_STATES_SAVE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let changes = states.lock().unwrap().take_changes();
+        let result = match changes {
+            Ok(Some((path, contents))) => {
+                tokio::task::spawn_blocking(move || write_atomically(&path, &contents))
+                    .await
+                    .map_err(eyre::Report::from)
+                    .and_then(|result| result)
+            }
+            Ok(None) => continue,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            warn!(error = ?e, "failed to save last light states");
+            // Try again next time around
+            states.lock().unwrap().dirty = true;
+        }
+    }
+}
+
+/// Write to a temporary file first so a crash never leaves a truncated file
+/// behind
+pub(crate) fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let tmp = path.with_extension("toml.tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::{
+        fade::Transition,
+        schedule::{At, StateChange},
+    };
+
+    /// A fresh directory for a test's files
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gvm-config-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn config_round_trips_through_toml() {
+        let dir = test_dir("round-trip");
+        let path = dir.join("config.toml");
+
+        let mut config = Config::load(&path).unwrap();
+        config
+            .update(|config| {
+                *config.light_mut("a4:c1:38:00:11:22") = LightConfig {
+                    name: Some("Key".into()),
+                    default_state: Some(LightState {
+                        intensity: 70,
+                        ..Default::default()
+                    }),
+                    groups: vec!["stage".into()],
+                    auto_connect: false,
+                    circadian: true,
+                };
+                config.presets.insert(
+                    "warm".into(),
+                    Preset {
+                        lights: [("a4:c1:38:00:11:22".into(), LightState::default())].into(),
+                        fade: 2.5,
+                    },
+                );
+                config.location = Some(Location {
+                    latitude: 51.5,
+                    longitude: -0.1,
+                });
+                config.schedule.push(Rule {
+                    days: Vec::new(),
+                    at: At::Sunset(-15),
+                    light: Some("stage".into()),
+                    fade: 30.0,
+                    change: StateChange {
+                        intensity: Some(40),
+                        ..Default::default()
+                    },
+                });
+                config.circadian.interval = 30.0;
+                config.fade_rate = Some(20.0);
+            })
+            .unwrap();
+
+        let loaded = Config::load(&path).unwrap();
+        assert_eq!(
+            toml::to_string(&loaded).unwrap(),
+            toml::to_string(&config).unwrap()
+        );
+        assert_eq!(
+            loaded.light("a4:c1:38:00:11:22"),
+            config.light("a4:c1:38:00:11:22")
+        );
+        assert_eq!(loaded.presets, config.presets);
+        assert_eq!(loaded.schedule, config.schedule);
+        assert_eq!(loaded.circadian, config.circadian);
+        assert_eq!(loaded.group_members("stage"), ["a4:c1:38:00:11:22"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [lights."a4:c1:38:00:11:22"]
+            name = "Key"
+            "#,
+        )
+        .unwrap();
+
+        let light = config.light("a4:c1:38:00:11:22").unwrap();
+        assert_eq!(light.name.as_deref(), Some("Key"));
+        assert!(light.auto_connect);
+        assert!(!light.circadian);
+        assert_eq!(config.circadian, Circadian::default());
+        assert!(config.presets.is_empty());
+        assert_eq!(config.fade_rate, None);
+    }
+
+    #[test]
+    fn updates_keep_changes_saved_by_others() {
+        let dir = test_dir("update");
+        let path = dir.join("config.toml");
+
+        // The GUI and a daemon each with their own copy
+        let mut gui = Config::load(&path).unwrap();
+        let mut daemon = Config::load(&path).unwrap();
+
+        gui.update(|config| config.light_mut("a").name = Some("Key".into()))
+            .unwrap();
+        daemon
+            .update(|config| config.light_mut("b").circadian = true)
+            .unwrap();
+
+        let saved = Config::load(&path).unwrap();
+        assert_eq!(saved.light("a").unwrap().name.as_deref(), Some("Key"));
+        assert!(saved.light("b").unwrap().circadian);
+
+        // and each copy catches up with the file when it saves
+        assert!(daemon.light("a").is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn last_states_round_trip() {
+        let dir = test_dir("last-states");
+        let config_path = dir.join("config.toml");
+
+        let state = LightState {
+            hue: 30,
+            ..Default::default()
+        };
+        let mut states = LastStates::load(&config_path).unwrap();
+        states.record("a", &state);
+        states.save().unwrap();
+        assert!(dir.join("last-state.toml").exists());
+
+        // Nothing to write when nothing changed
+        states.record("a", &state);
+        assert!(states.take_changes().unwrap().is_none());
+
+        let loaded = LastStates::load(&config_path).unwrap();
+        assert_eq!(loaded.get("a"), Some(&state));
+        assert_eq!(loaded.get("b"), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A light whose transitions can be watched
+    fn light(id: &str, state: LightState) -> (LightGuiState, watch::Receiver<Transition>) {
+        let (tx, rx) = watch::channel(Transition::new(state.clone(), Duration::ZERO, None));
+        (LightGuiState::new(id, id, Vec::new(), state, tx), rx)
+    }
+
+    #[test]
+    fn presets_capture_and_recall_lights() {
+        let dim = LightState {
+            intensity: 5,
+            ..Default::default()
+        };
+        let bright = LightState {
+            intensity: 95,
+            ..Default::default()
+        };
+
+        let (a, a_rx) = light("a", dim.clone());
+        let (b, _) = light("b", bright.clone());
+        let mut lights = vec![a, b];
+
+        let mut preset = Preset::capture(&lights);
+        preset.fade = 1.5;
+        assert_eq!(
+            preset.lights,
+            BTreeMap::from([("a".into(), dim.clone()), ("b".into(), bright.clone())])
+        );
+
+        // Survives being saved with the config
+        let mut config = Config::default();
+        config.presets.insert("look".into(), preset);
+        let config: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        let mut preset = config.presets["look"].clone();
+
+        // Lights missing from the preset, or missing from the list, are skipped
+        preset.lights.remove("b");
+        preset.lights.insert("c".into(), dim.clone());
+
+        lights[0].set_state(bright.clone());
+        lights[1].set_state(dim.clone());
+        preset.recall(&mut lights);
+
+        assert_eq!(lights[0].state(), &dim);
+        assert_eq!(lights[1].state(), &dim);
+        let transition = a_rx.borrow();
+        assert_eq!(transition.state, dim);
+        assert_eq!(transition.fade, Duration::from_secs_f32(1.5));
+    }
+
+    #[test]
+    fn negative_preset_fades_are_instant() {
+        let preset = Preset {
+            fade: -1.0,
+            ..Default::default()
+        };
+        assert_eq!(preset.fade_duration(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use gvm::{
+    simulator::SimulatorTransport, transport::LightTransport, AdapterSelector, Discovery, Light,
+    MacAddress,
+};
+use tokio::{sync::watch, time::sleep};
+use tokio_stream::wrappers::WatchStream;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{Config, LastStates},
+    fade::{self, Transition, DEFAULT_FADE_RATE},
+    gui::LightGuiState,
+};
+
+/// Useful for debugging GUI when there are no lights available to connect to -
+/// Slowly yields simulated lights that the GUI sees as connected. The simulated
+/// lights decode the same packets real ones would and log the resulting light
+/// state at INFO level.
+pub(crate) async fn scan_and_spawn_demo_mode(
+    lights: Arc<Mutex<Vec<LightGuiState>>>,
+    config: Arc<Mutex<Config>>,
+    last_states: Arc<Mutex<LastStates>>,
+) {
+    for (id, delay) in [(1, 0), (2, 5), (3, 10)] {
+        sleep(Duration::from_secs(delay)).await;
+
+        let mac = [0x5e, 0x00, 0x00, 0x00, 0x00, id];
+        let led = Light::new(SimulatorTransport::new(
+            format!("simulated-{id}"),
+            Some(mac),
+        ))
+        .await;
+
+        let name = format!("LED {id}");
+        if let Some(gui_state) = spawn_connection(led, name, &config, &last_states).await {
+            lights.lock().unwrap().push(gui_state);
+        }
+    }
+}
+
+/// Run a loop that continuously scans `adapters` for new compatible LEDs,
+/// spawns connection managers for those lights, and adds them to the GUI.
+pub(crate) async fn scan_and_spawn(
+    lights: Arc<Mutex<Vec<LightGuiState>>>,
+    config: Arc<Mutex<Config>>,
+    last_states: Arc<Mutex<LastStates>>,
+    adapters: Vec<AdapterSelector>,
+) {
+    let mut device_stream = Discovery::on_adapters(adapters);
+
+    while let Some(led) = device_stream.next().await {
+        let led = match led {
+            Ok(x) => x,
+            Err(e) => {
+                error!(error = ?e, "error scanning for devices");
+                continue;
+            }
+        };
+
+        let name = match led.mac() {
+            MacAddress::Unknown => String::from("New LED"),
+            mac @ MacAddress::Known(_) => format!("{mac:?}"),
+        };
+
+        if let Some(gui_state) = spawn_connection(led, name, &config, &last_states).await {
+            lights.lock().unwrap().push(gui_state);
+        }
+    }
+
+    warn!("Scanning stream hung up");
+}
+
+/// Look up the configuration for a newly connected light and spawn its
+/// connection manager, returning the state the GUI should show for it. Lights
+/// configured not to auto-connect are disconnected instead.
+///
+/// The light starts in its configured default state if it has one, otherwise in
+/// the last state it was left in.
+async fn spawn_connection(
+    led: Light<impl LightTransport>,
+    default_name: String,
+    config: &Mutex<Config>,
+    last_states: &Arc<Mutex<LastStates>>,
+) -> Option<LightGuiState> {
+    let id = led.id();
+
+    let (light_config, fade_rate) = {
+        let config = config.lock().unwrap();
+        let light_config = config.light(&id).cloned().unwrap_or_default();
+
+        (light_config, config.fade_rate.unwrap_or(DEFAULT_FADE_RATE))
+    };
+
+    if !light_config.auto_connect {
+        info!(peripheral_id = %led.transport().id(), peripheral_mac = ?led.mac(), "auto-connect disabled, disconnecting");
+        if let Err(e) = led.disconnect().await {
+            warn!(peripheral_id = %led.transport().id(), error = ?e, "failed to disconnect");
+        }
+        return None;
+    }
+
+    let name = light_config.name.unwrap_or(default_name);
+    let state = light_config
+        .default_state
+        .or_else(|| last_states.lock().unwrap().get(&id).cloned())
+        .unwrap_or_default();
+
+    let (tx, rx) = watch::channel(Transition::new(state.clone(), Duration::ZERO, None));
+    let mut gui_state =
+        LightGuiState::new(id.clone(), name, light_config.groups, state.clone(), tx);
+    gui_state.set_circadian(light_config.circadian);
+
+    // Only the target of a fade is recorded as the last state, not each step
+    // along the way
+    let last_states = last_states.clone();
+    let rx = fade::throttle(WatchStream::from_changes(rx), Duration::from_millis(100)).inspect(
+        move |transition: &Transition| {
+            last_states.lock().unwrap().record(&id, &transition.state);
+        },
+    );
+    let rx = fade::fade(state.clone(), rx, fade_rate);
+
+    // The light is only handed back to discovery once its connection manager
+    // has ended and dropped it
+    let peripheral_id = led.transport().id();
+    tokio::spawn(async move {
+        match led.run(state, rx).await {
+            Ok(()) => info!(%peripheral_id, "connection manager stopped"),
+            Err(e) => error!(%peripheral_id, error = ?e, "connection manager failed"),
+        }
+    });
+
+    Some(gui_state)
+}
@@ -0,0 +1,747 @@
+//! The daemon and its clients talk over a unix socket, so everything but the
+//! message types is only built on unix.
+
+#[cfg(unix)]
+use std::{collections::HashMap, os::unix::fs::PermissionsExt, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[cfg(unix)]
+use eyre::eyre;
+use eyre::{bail, Result};
+#[cfg(unix)]
+use futures::{
+    stream::{BoxStream, SelectAll},
+    StreamExt,
+};
+use gvm::LightState;
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    select,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver},
+        watch,
+    },
+};
+#[cfg(unix)]
+use tokio_stream::wrappers::WatchStream;
+#[cfg(unix)]
+use tracing::{info, warn};
+
+#[cfg(unix)]
+use crate::{circadian, config::Config, effects, fade::Transition, show::Sequencer};
+use crate::{effects::Effect, gui::LightGuiState};
+
+/// How often subscribed clients are checked for newly connected lights. Changes
+/// to lights they already know about are pushed straight away.
+#[cfg(unix)]
+const NEW_LIGHT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A single line sent by a client to the daemon
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub(crate) enum Request {
+    List,
+    Get {
+        light: String,
+    },
+    /// Set a single light, or every light in a group if `light` is a group
+    /// name. This takes the lights off the circadian curves.
+    Set {
+        light: String,
+        state: LightState,
+
+        /// Seconds to fade to the new state over
+        #[serde(default)]
+        fade: f32,
+    },
+    /// Put a light, or every light in a group, on or off the circadian curves
+    Circadian {
+        light: String,
+        enabled: bool,
+    },
+    /// Receive a `Changed` line for every light whenever its state changes
+    Subscribe,
+    /// The names of the cues in the show and which one is current
+    Cues,
+    /// Start the next cue
+    Go,
+    /// Return to the previous cue
+    Back,
+    /// Start a cue by number (counting from 1) or name
+    Jump {
+        cue: String,
+    },
+    /// Start a host effect on a light or every light in a group, or stop it
+    /// if `effect` is missing
+    Effect {
+        light: String,
+        #[serde(default)]
+        effect: Option<Effect>,
+    },
+}
+
+/// A single line sent by the daemon to a client
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Response {
+    Lights {
+        lights: Vec<LightInfo>,
+    },
+    Light {
+        light: LightInfo,
+    },
+    Ok,
+    Error {
+        message: String,
+    },
+    /// The light, group or cue asked for doesn't exist
+    NotFound {
+        message: String,
+    },
+    Changed {
+        light: LightInfo,
+    },
+    /// `current` is an index into `cues`
+    Cues {
+        cues: Vec<String>,
+        current: Option<usize>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct LightInfo {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub state: LightState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<Effect>,
+
+    /// Whether the light follows the circadian curves
+    #[serde(default)]
+    pub circadian: bool,
+}
+
+impl From<&LightGuiState> for LightInfo {
+    fn from(light: &LightGuiState) -> Self {
+        Self {
+            id: light.id().to_owned(),
+            name: light.name().to_owned(),
+            groups: light.groups().to_vec(),
+            state: light.state().clone(),
+            effect: light.effect().cloned(),
+            circadian: light.circadian(),
+        }
+    }
+}
+
+/// Where the daemon listens when no socket path is given
+#[cfg(unix)]
+pub(crate) fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("gvm-led-control.sock"),
+        None => std::env::temp_dir().join("gvm-led-control.sock"),
+    }
+}
+
+/// The socket commands should go through: `socket` if one was given, otherwise
+/// the default one if a daemon answers on it
+#[cfg(unix)]
+pub(crate) fn find_socket(socket: Option<PathBuf>) -> Option<PathBuf> {
+    socket.or_else(|| {
+        let path = default_socket_path();
+        is_listening(&path).then_some(path)
+    })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn find_socket(socket: Option<PathBuf>) -> Option<PathBuf> {
+    socket
+}
+
+/// Whether a daemon answers on `path`
+#[cfg(unix)]
+pub(crate) fn is_listening(path: &Path) -> bool {
+    std::os::unix::net::UnixStream::connect(path).is_ok()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_listening(_path: &Path) -> bool {
+    false
+}
+
+/// Accept clients on a unix socket forever, letting them inspect and control
+/// the lights found by the bluetooth module and run the show
+#[cfg(unix)]
+pub(crate) async fn serve(
+    path: &Path,
+    lights: Arc<Mutex<Vec<LightGuiState>>>,
+    config: Arc<Mutex<Config>>,
+    sequencer: Sequencer,
+) -> Result<()> {
+    // A socket left behind by a previous daemon would make bind fail, but one
+    // that still answers belongs to a daemon that is running
+    if path.exists() {
+        if is_listening(path) {
+            bail!("a daemon is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    // Anyone who can connect can control the lights, and the fallback
+    // location is shared with every other user
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!(path = %path.display(), "daemon listening");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let lights = lights.clone();
+        let config = config.clone();
+        let sequencer = sequencer.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, lights, config, sequencer).await {
+                warn!(error = ?e, "daemon client failed");
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn handle_client(
+    stream: UnixStream,
+    lights: Arc<Mutex<Vec<LightGuiState>>>,
+    config: Arc<Mutex<Config>>,
+    sequencer: Sequencer,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    // Once subscribed, what the client was last told about each light, and
+    // the transitions of each light by its position in `lights`. Lights are
+    // never removed, so positions stay put.
+    let mut subscribed = false;
+    let mut sent: Vec<LightInfo> = Vec::new();
+    let mut transitions: SelectAll<BoxStream<'static, usize>> = SelectAll::new();
+    let mut new_lights = tokio::time::interval(NEW_LIGHT_INTERVAL);
+
+    loop {
+        let responses = select! {
+            line = lines.next_line() => {
+                let line = match line? {
+                    Some(x) => x,
+                    None => break,
+                };
+
+                let response = match serde_json::from_str(&line) {
+                    Ok(Request::Subscribe) => {
+                        subscribed = true;
+                        watch_new_lights(&lights, &mut sent, &mut transitions);
+                        Response::Ok
+                    }
+                    Ok(request) => handle_request(request, &lights, &config, &sequencer),
+                    Err(e) => Response::Error {
+                        message: format!("invalid request: {e}"),
+                    },
+                };
+
+                vec![response]
+            }
+            Some(index) = transitions.next(), if subscribed => {
+                let light = LightInfo::from(&lights.lock().unwrap()[index]);
+                if light == sent[index] {
+                    continue;
+                }
+
+                sent[index] = light.clone();
+                vec![Response::Changed { light }]
+            }
+            _ = new_lights.tick(), if subscribed => {
+                watch_new_lights(&lights, &mut sent, &mut transitions)
+                    .into_iter()
+                    .map(|light| Response::Changed { light })
+                    .collect()
+            }
+        };
+
+        for response in responses {
+            write_line(&mut writer, &response).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Start following the changes to every light a subscribed client hasn't seen
+/// yet, returning those lights
+#[cfg(unix)]
+fn watch_new_lights(
+    lights: &Mutex<Vec<LightGuiState>>,
+    sent: &mut Vec<LightInfo>,
+    transitions: &mut SelectAll<BoxStream<'static, usize>>,
+) -> Vec<LightInfo> {
+    let lights = lights.lock().unwrap();
+    let new: Vec<_> = lights[sent.len()..].iter().map(LightInfo::from).collect();
+
+    for (index, light) in lights.iter().enumerate().skip(sent.len()) {
+        let changes = WatchStream::from_changes(light.subscribe()).map(move |_| index);
+        transitions.push(changes.boxed());
+    }
+    sent.extend(new.iter().cloned());
+
+    new
+}
+
+/// Write a request or response as a single line
+#[cfg(unix)]
+async fn write_line(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &impl Serialize,
+) -> Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn handle_request(
+    request: Request,
+    lights: &Mutex<Vec<LightGuiState>>,
+    config: &Mutex<Config>,
+    sequencer: &Sequencer,
+) -> Response {
+    // Cue requests only need the show, which is never locked at the same time
+    // as the lights
+    match request {
+        Request::Cues => {
+            return Response::Cues {
+                cues: sequencer
+                    .show()
+                    .cues
+                    .into_iter()
+                    .map(|cue| cue.name)
+                    .collect(),
+                current: sequencer.current(),
+            }
+        }
+        Request::Go => {
+            sequencer.go();
+            return Response::Ok;
+        }
+        Request::Back => {
+            sequencer.back();
+            return Response::Ok;
+        }
+        Request::Jump { cue } => {
+            return match sequencer.show().find(&cue) {
+                Some(index) => {
+                    sequencer.jump(index);
+                    Response::Ok
+                }
+                None => Response::NotFound {
+                    message: format!("no cue '{cue}' in the show"),
+                },
+            }
+        }
+        _ => {}
+    }
+
+    let mut lights = lights.lock().unwrap();
+
+    match request {
+        Request::List => Response::Lights {
+            lights: lights.iter().map(LightInfo::from).collect(),
+        },
+        Request::Get { light: id } => match lights.iter().find(|light| light.id() == id) {
+            Some(light) => Response::Light {
+                light: light.into(),
+            },
+            None => not_found(&id),
+        },
+        Request::Set {
+            light: target,
+            state,
+            fade,
+        } => {
+            let fade = Duration::try_from_secs_f32(fade).unwrap_or_default();
+
+            let mut found = false;
+            for light in lights.iter_mut().filter(|light| light.matches(&target)) {
+                light.fade_to(state.clone(), fade);
+                found = true;
+            }
+
+            if found {
+                circadian::save_modes(config, &lights);
+                Response::Ok
+            } else {
+                not_found(&target)
+            }
+        }
+        Request::Circadian {
+            light: target,
+            enabled,
+        } => {
+            let curves = config.lock().unwrap().circadian.clone();
+
+            let mut found = false;
+            for light in lights.iter_mut().filter(|light| light.matches(&target)) {
+                // Move straight onto the curves rather than waiting for the
+                // next update
+                light.set_circadian(enabled);
+                curves.update(light);
+                found = true;
+            }
+
+            if found {
+                circadian::save_modes(config, &lights);
+                Response::Ok
+            } else {
+                not_found(&target)
+            }
+        }
+        Request::Cues | Request::Go | Request::Back | Request::Jump { .. } => {
+            unreachable!("cue requests are handled above")
+        }
+        Request::Effect {
+            light: target,
+            effect,
+        } => {
+            let matching: Vec<_> = lights
+                .iter_mut()
+                .filter(|light| light.matches(&target))
+                .collect();
+
+            if matching.is_empty() {
+                not_found(&target)
+            } else {
+                effects::start(effect.as_ref(), matching);
+                Response::Ok
+            }
+        }
+        Request::Subscribe => unreachable!("subscriptions are handled per client"),
+    }
+}
+
+#[cfg(unix)]
+fn not_found(id: &str) -> Response {
+    Response::NotFound {
+        message: format!("no light or group named '{id}'"),
+    }
+}
+
+/// The error [`request`] fails with when the daemon has no light, group or cue
+/// by the name given
+#[derive(Debug)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) struct NotFound(String);
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Send a single request to a running daemon and wait for its response
+#[cfg(unix)]
+pub(crate) async fn request(path: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+    write_line(&mut writer, request).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| eyre!("daemon hung up without responding"))?;
+
+    match serde_json::from_str(&line)? {
+        Response::Error { message } => bail!("daemon error: {message}"),
+        Response::NotFound { message } => Err(NotFound(message).into()),
+        response => Ok(response),
+    }
+}
+
+/// Fill `lights` with the lights of a running daemon and keep them up to date,
+/// for a GUI that leaves the connections to the daemon. Changes made to the
+/// lights here are sent on to the daemon over the same connection. Only
+/// returns if the daemon goes away.
+#[cfg(unix)]
+pub(crate) async fn mirror(path: PathBuf, lights: Arc<Mutex<Vec<LightGuiState>>>) -> Result<()> {
+    let stream = UnixStream::connect(&path).await?;
+    let (reader, writer) = stream.into_split();
+
+    // Requests are written by their own task, so that reading the daemon's
+    // lines never waits on the daemon reading ours
+    let (requests, outgoing) = unbounded_channel();
+    tokio::spawn(async move {
+        if let Err(e) = write_requests(writer, outgoing).await {
+            warn!(error = ?e, "failed to send to the daemon");
+        }
+    });
+
+    // Subscribing first means no change can slip in between the list and the
+    // subscription
+    _ = requests.send(Request::Subscribe);
+    _ = requests.send(Request::List);
+
+    // Changes made here to each light, with the transition if there was one,
+    // what the daemon last reported for it, and whether it was last seen on
+    // the circadian curves here
+    let mut changes: SelectAll<BoxStream<'static, (String, Option<Transition>)>> = SelectAll::new();
+    let mut remote: HashMap<String, LightInfo> = HashMap::new();
+    let mut circadian: HashMap<String, bool> = HashMap::new();
+
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    break;
+                };
+
+                let infos = match serde_json::from_str(&line)? {
+                    Response::Lights { lights } => lights,
+                    Response::Changed { light } => vec![light],
+                    Response::Error { message } | Response::NotFound { message } => {
+                        warn!(%message, "daemon rejected a change");
+                        continue;
+                    }
+                    _ => continue,
+                };
+
+                let mut lights = lights.lock().unwrap();
+                for info in infos {
+                    // Only a change to the daemon's circadian mode is taken
+                    // on, so a report sent before a change made here can't
+                    // undo it
+                    let toggled = remote
+                        .insert(info.id.clone(), info.clone())
+                        .is_none_or(|previous| previous.circadian != info.circadian);
+                    if toggled {
+                        circadian.insert(info.id.clone(), info.circadian);
+                    }
+
+                    if let Some(light) = lights.iter_mut().find(|light| light.id() == info.id) {
+                        light.show_state(info.state);
+                        if toggled {
+                            light.set_circadian(info.circadian);
+                        }
+                        continue;
+                    }
+
+                    let transition = Transition::new(info.state.clone(), Duration::ZERO, None);
+                    let (tx, rx) = watch::channel(transition);
+                    let mut light =
+                        LightGuiState::new(info.id, info.name, info.groups, info.state, tx);
+                    light.set_circadian(info.circadian);
+
+                    let id = light.id().to_owned();
+                    let transitions =
+                        WatchStream::from_changes(rx).map(move |x| (id.clone(), Some(x)));
+                    let id = light.id().to_owned();
+                    let others = WatchStream::from_changes(light.subscribe())
+                        .map(move |_| (id.clone(), None));
+                    changes.push(transitions.boxed());
+                    changes.push(others.boxed());
+
+                    lights.push(light);
+                }
+            }
+            Some((id, transition)) = changes.next() => {
+                let Some(remote) = remote.get_mut(&id) else {
+                    continue;
+                };
+
+                let on_curves = lights
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|light| light.id() == id)
+                    .is_some_and(|light| light.circadian());
+
+                if let Some(transition) = transition {
+                    // A light still on the curves was moved along them, which
+                    // the daemon does itself. Setting it there would take it
+                    // off them.
+                    if !on_curves {
+                        _ = requests.send(Request::Set {
+                            light: id.clone(),
+                            state: transition.state,
+                            fade: transition.fade.as_secs_f32(),
+                        });
+                    }
+
+                    let effect = transition.effect.map(|run| run.effect);
+                    if remote.effect != effect {
+                        remote.effect = effect.clone();
+                        _ = requests.send(Request::Effect { light: id.clone(), effect });
+                    }
+                }
+
+                if circadian.insert(id.clone(), on_curves) != Some(on_curves)
+                    && remote.circadian != on_curves
+                {
+                    _ = requests.send(Request::Circadian { light: id, enabled: on_curves });
+                }
+            }
+        }
+    }
+
+    bail!("daemon hung up")
+}
+
+#[cfg(unix)]
+async fn write_requests(
+    mut writer: OwnedWriteHalf,
+    mut requests: UnboundedReceiver<Request>,
+) -> Result<()> {
+    while let Some(request) = requests.recv().await {
+        write_line(&mut writer, &request).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn mirror(_path: PathBuf, _lights: Arc<Mutex<Vec<LightGuiState>>>) -> Result<()> {
+    bail!("the daemon is only available on unix")
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn request(_path: &Path, _request: &Request) -> Result<Response> {
+    bail!("the daemon is only available on unix")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// Wait up to a few seconds for `done` to hold
+    async fn eventually(mut done: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out");
+    }
+
+    #[tokio::test]
+    async fn mirror_follows_and_controls_the_daemon() {
+        let dir = std::env::temp_dir().join(format!("gvm-daemon-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socket");
+
+        let (tx, mut rx) =
+            watch::channel(Transition::new(LightState::default(), Duration::ZERO, None));
+        let light = LightGuiState::new("light", "Light", Vec::new(), LightState::default(), tx);
+        let lights = Arc::new(Mutex::new(vec![light]));
+        let config = Arc::new(Mutex::new(Config::load(&dir.join("config.toml")).unwrap()));
+        let (sequencer, _) = Sequencer::new(dir.join("show.toml"), lights.clone()).unwrap();
+
+        tokio::spawn({
+            let path = path.clone();
+            let lights = lights.clone();
+            let config = config.clone();
+            async move { serve(&path, lights, config, sequencer).await }
+        });
+        eventually(|| is_listening(&path)).await;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mirrored = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(mirror(path.clone(), mirrored.clone()));
+        eventually(|| !mirrored.lock().unwrap().is_empty()).await;
+        assert_eq!(mirrored.lock().unwrap()[0].name(), "Light");
+
+        // Changes made on the daemon show up in the mirror
+        let mut state = LightState {
+            intensity: 42,
+            ..Default::default()
+        };
+        lights.lock().unwrap()[0].set_state(state.clone());
+        eventually(|| mirrored.lock().unwrap()[0].state() == &state).await;
+
+        // and changes made in the mirror reach the daemon's light
+        state.hue = 10;
+        mirrored.lock().unwrap()[0].set_state(state.clone());
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            rx.wait_for(|transition| transition.state == state),
+        )
+        .await
+        .expect("timed out")
+        .unwrap();
+
+        // Circadian mode follows the light both ways, and is saved
+        mirrored.lock().unwrap()[0].set_circadian(true);
+        eventually(|| lights.lock().unwrap()[0].circadian()).await;
+        assert!(config.lock().unwrap().light("light").unwrap().circadian);
+
+        lights.lock().unwrap()[0].set_circadian(false);
+        eventually(|| !mirrored.lock().unwrap()[0].circadian()).await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn setting_a_light_takes_it_off_the_curves() {
+        let dir = std::env::temp_dir().join(format!("gvm-circadian-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (tx, _rx) =
+            watch::channel(Transition::new(LightState::default(), Duration::ZERO, None));
+        let light = LightGuiState::new(
+            "light",
+            "Light",
+            vec!["all".into()],
+            LightState::default(),
+            tx,
+        );
+        let lights = Arc::new(Mutex::new(vec![light]));
+        let config = Mutex::new(Config::load(&dir.join("config.toml")).unwrap());
+        let (sequencer, _) = Sequencer::new(dir.join("show.toml"), lights.clone()).unwrap();
+
+        let request = Request::Circadian {
+            light: "all".into(),
+            enabled: true,
+        };
+        let response = handle_request(request, &lights, &config, &sequencer);
+        assert!(matches!(response, Response::Ok));
+        assert!(lights.lock().unwrap()[0].circadian());
+        assert!(config.lock().unwrap().light("light").unwrap().circadian);
+
+        let request = Request::Set {
+            light: "light".into(),
+            state: LightState::default(),
+            fade: 0.0,
+        };
+        let response = handle_request(request, &lights, &config, &sequencer);
+        assert!(matches!(response, Response::Ok));
+        assert!(!lights.lock().unwrap()[0].circadian());
+        assert!(!config.lock().unwrap().light("light").unwrap().circadian);
+
+        let request = Request::Effect {
+            light: "nobody".into(),
+            effect: None,
+        };
+        let response = handle_request(request, &lights, &config, &sequencer);
+        assert!(matches!(response, Response::NotFound { .. }));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
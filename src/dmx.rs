@@ -0,0 +1,399 @@
+use std::{
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use eyre::{bail, eyre, Result};
+use gvm::{
+    protocol::{Scene, HUE_RANGE},
+    LightMode, LightState,
+};
+use tokio::net::UdpSocket;
+use tracing::{info, trace, warn};
+
+use crate::gui::LightGuiState;
+
+const SACN_PORT: u16 = 5568;
+const ARTNET_PORT: u16 = 6454;
+
+const SACN_ACN_ID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const ARTNET_ID: &[u8; 8] = b"Art-Net\0";
+const ARTNET_OP_DMX: u16 = 0x5000;
+
+/// Channel layout used when a patch doesn't list its own
+const DEFAULT_FOOTPRINT: [Channel; 5] = [
+    Channel::Intensity,
+    Channel::Cct,
+    Channel::Hue,
+    Channel::Saturation,
+    Channel::Mode,
+];
+
+/// What a single DMX channel in a light's footprint controls
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Channel {
+    /// 0 - 127 off, 128 - 255 on
+    Power,
+    Intensity,
+    /// Full range maps onto 3200K - 5600K
+    Cct,
+    /// Full range maps onto the light's hue range
+    Hue,
+    Saturation,
+    /// 0 - 84 CCT, 85 - 169 HSI, 170 - 255 scene
+    Mode,
+    /// Full range is split evenly between the built-in scenes
+    Scene,
+}
+
+impl FromStr for Channel {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "power" => Self::Power,
+            "intensity" => Self::Intensity,
+            "cct" => Self::Cct,
+            "hue" => Self::Hue,
+            "saturation" => Self::Saturation,
+            "mode" => Self::Mode,
+            "scene" => Self::Scene,
+            _ => bail!("unknown DMX channel '{s}'"),
+        })
+    }
+}
+
+/// Where a light (or every light in a group) lives in DMX space: a universe, the (1-based) address of its
+/// first channel, and the channels it occupies from there on. Universes are
+/// 1-based too, as sACN numbers them.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DmxPatch {
+    pub light: String,
+    pub universe: u16,
+    pub address: u16,
+    pub footprint: Vec<Channel>,
+}
+
+impl FromStr for DmxPatch {
+    type Err = eyre::Report;
+
+    /// Parses `<light>=<universe>/<address>[:<channel>,<channel>...]`, e.g.
+    /// `a4:c1:38:00:11:22=1/17:intensity,cct`
+    fn from_str(s: &str) -> Result<Self> {
+        let (light, location) = s.rsplit_once('=').ok_or_else(|| {
+            eyre!("DMX patch '{s}' should look like <light>=<universe>/<address>")
+        })?;
+
+        let (location, footprint) = match location.split_once(':') {
+            Some((location, channels)) => (
+                location,
+                channels
+                    .split(',')
+                    .map(Channel::from_str)
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            None => (location, DEFAULT_FOOTPRINT.to_vec()),
+        };
+
+        let (universe, address) = location
+            .split_once('/')
+            .ok_or_else(|| eyre!("DMX patch '{s}' is missing a /<address>"))?;
+        let universe = universe.parse()?;
+        let address = address.parse()?;
+
+        if universe == 0 {
+            bail!("DMX patch '{s}' has universe 0, universes count from 1");
+        }
+        if !(1..=512).contains(&address) || address as usize + footprint.len() - 1 > 512 {
+            bail!("DMX patch '{s}' does not fit in a universe");
+        }
+
+        Ok(Self {
+            light: light.to_owned(),
+            universe,
+            address,
+            footprint,
+        })
+    }
+}
+
+impl DmxPatch {
+    /// Update a light's state from the slots of a universe this light is
+    /// patched into. Slots missing from a short frame are left alone.
+    fn apply(&self, slots: &[u8], state: &mut LightState) {
+        let start = self.address as usize - 1;
+
+        for (channel, value) in self.footprint.iter().zip(slots.iter().skip(start)) {
+            let scale = |max: u16| (*value as u16 * max / 255) as u8;
+
+            match channel {
+                Channel::Power => state.enabled = *value >= 128,
+                Channel::Intensity => state.intensity = scale(100),
+                Channel::Cct => state.temperature = 32 + scale(24),
+                Channel::Hue => state.hue = scale((HUE_RANGE - 1).into()),
+                Channel::Saturation => state.saturation = scale(100),
+                Channel::Mode => {
+                    state.mode = match value {
+                        0..=84 => LightMode::Cct,
+                        85..=169 => LightMode::Hsi,
+                        _ => LightMode::Scene,
+                    }
+                }
+                Channel::Scene => {
+                    let index = *value as usize * Scene::ALL.len() / 256;
+                    state.scene = Scene::ALL[index];
+                }
+            }
+        }
+    }
+}
+
+/// Listen for sACN and Art-Net forever, applying frames for patched universes
+/// to the lights
+pub(crate) async fn run(
+    patches: Vec<DmxPatch>,
+    lights: Arc<Mutex<Vec<LightGuiState>>>,
+) -> Result<()> {
+    let sacn = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SACN_PORT)).await?;
+    let artnet = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, ARTNET_PORT)).await?;
+
+    // sACN is multicast to a group per universe
+    for patch in &patches {
+        let [high, low] = patch.universe.to_be_bytes();
+        let group = Ipv4Addr::new(239, 255, high, low);
+        if let Err(e) = sacn.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED) {
+            warn!(error = ?e, %group, "failed to join sACN multicast group");
+        }
+    }
+
+    info!(patches = patches.len(), "listening for sACN and Art-Net");
+
+    let mut sacn_buf = [0; 1144];
+    let mut artnet_buf = [0; 1144];
+
+    loop {
+        let frame = tokio::select! {
+            received = sacn.recv(&mut sacn_buf) => {
+                parse_sacn(&sacn_buf[..received?])
+                    .map(|(universe, slots)| (universe, slots.to_vec()))
+            }
+            received = artnet.recv(&mut artnet_buf) => {
+                parse_artnet(&artnet_buf[..received?])
+                    .map(|(universe, slots)| (universe, slots.to_vec()))
+            }
+        };
+
+        let Some((universe, slots)) = frame else {
+            continue;
+        };
+        trace!(universe, len = slots.len(), "DMX frame");
+
+        let mut lights = lights.lock().unwrap();
+        for patch in patches.iter().filter(|patch| patch.universe == universe) {
+            for light in lights
+                .iter_mut()
+                .filter(|light| light.matches(&patch.light))
+            {
+                let mut state = light.state().clone();
+                patch.apply(&slots, &mut state);
+                if state != *light.state() {
+                    light.set_state(state);
+                }
+            }
+        }
+    }
+}
+
+/// Extract the universe and DMX slots from an E1.31 data packet
+fn parse_sacn(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < 126 || &packet[4..16] != SACN_ACN_ID {
+        return None;
+    }
+
+    // Root vector VECTOR_ROOT_E131_DATA, framing vector VECTOR_E131_DATA_PACKET
+    // and DMP vector VECTOR_DMP_SET_PROPERTY
+    if packet[18..22] != [0, 0, 0, 4] || packet[40..44] != [0, 0, 0, 2] || packet[117] != 0x02 {
+        return None;
+    }
+
+    let universe = u16::from_be_bytes([packet[113], packet[114]]);
+    let count = u16::from_be_bytes([packet[123], packet[124]]) as usize;
+
+    // The first property is the start code, only the null start code carries
+    // levels
+    if count == 0 || packet[125] != 0 {
+        return None;
+    }
+
+    let end = (125 + count).min(packet.len());
+    Some((universe, &packet[126..end]))
+}
+
+/// Extract the universe and DMX slots from an ArtDmx packet. Art-Net counts
+/// port addresses from 0, so they are shifted up by one to match sACN.
+fn parse_artnet(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < 18 || &packet[..8] != ARTNET_ID {
+        return None;
+    }
+
+    if u16::from_le_bytes([packet[8], packet[9]]) != ARTNET_OP_DMX {
+        return None;
+    }
+
+    let universe = (u16::from_le_bytes([packet[14], packet[15]]) & 0x7fff) + 1;
+    let len = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+
+    let end = (18 + len).min(packet.len());
+    Some((universe, &packet[18..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An E1.31 data packet for `universe` carrying `slots`
+    fn sacn_packet(universe: u16, slots: &[u8]) -> Vec<u8> {
+        let count = (slots.len() + 1) as u16;
+        let pdu_length = |len: usize| (0x7000 | len as u16).to_be_bytes();
+
+        let mut packet = Vec::new();
+        // Root layer: preamble size, postamble size, ACN packet identifier,
+        // flags and length, vector and sender CID
+        packet.extend([0x00, 0x10, 0x00, 0x00]);
+        packet.extend(SACN_ACN_ID);
+        packet.extend(pdu_length(110 + slots.len()));
+        packet.extend([0x00, 0x00, 0x00, 0x04]);
+        packet.extend([0xab; 16]);
+        // Framing layer: flags and length, vector, source name, priority,
+        // synchronization address, sequence number, options and universe
+        packet.extend(pdu_length(88 + slots.len()));
+        packet.extend([0x00, 0x00, 0x00, 0x02]);
+        packet.extend([0; 64]);
+        packet.extend([100, 0x00, 0x00, 0x01, 0x00]);
+        packet.extend(universe.to_be_bytes());
+        // DMP layer: flags and length, vector, address type and data type,
+        // first property address, address increment, property value count,
+        // start code and the slots
+        packet.extend(pdu_length(11 + slots.len()));
+        packet.extend([0x02, 0xa1, 0x00, 0x00, 0x00, 0x01]);
+        packet.extend(count.to_be_bytes());
+        packet.push(0x00);
+        packet.extend(slots);
+
+        assert_eq!(packet.len(), 126 + slots.len());
+        packet
+    }
+
+    /// An ArtDmx packet for port address `port_address` carrying `slots`
+    fn artnet_packet(port_address: u16, slots: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        // ID, OpCode, protocol version, sequence, physical port, SubUni and
+        // Net, length
+        packet.extend(ARTNET_ID);
+        packet.extend(ARTNET_OP_DMX.to_le_bytes());
+        packet.extend([0x00, 0x0e, 0x01, 0x00]);
+        packet.extend(port_address.to_le_bytes());
+        packet.extend((slots.len() as u16).to_be_bytes());
+        packet.extend(slots);
+        packet
+    }
+
+    #[test]
+    fn parse_sacn_packet() {
+        let packet = sacn_packet(3, &[10, 20, 30]);
+        assert_eq!(parse_sacn(&packet), Some((3, &[10, 20, 30][..])));
+
+        // Alternate start codes carry something other than levels
+        let mut alternate = packet.clone();
+        alternate[125] = 0xdd;
+        assert_eq!(parse_sacn(&alternate), None);
+
+        let mut other_vector = packet.clone();
+        other_vector[21] = 0x08;
+        assert_eq!(parse_sacn(&other_vector), None);
+
+        assert_eq!(parse_sacn(&packet[..100]), None);
+        assert_eq!(parse_sacn(&artnet_packet(2, &[10, 20, 30])), None);
+    }
+
+    #[test]
+    fn parse_artnet_packet() {
+        let packet = artnet_packet(2, &[10, 20, 30, 40]);
+        assert_eq!(parse_artnet(&packet), Some((3, &[10, 20, 30, 40][..])));
+
+        // Port address 0 is the first universe
+        assert_eq!(parse_artnet(&artnet_packet(0, &[1])), Some((1, &[1][..])));
+
+        let mut poll = packet.clone();
+        poll[8..10].copy_from_slice(&0x2000u16.to_le_bytes());
+        assert_eq!(parse_artnet(&poll), None);
+
+        assert_eq!(parse_artnet(&packet[..10]), None);
+        assert_eq!(parse_artnet(&sacn_packet(3, &[10])), None);
+    }
+
+    #[test]
+    fn parse_patch() {
+        let patch: DmxPatch = "a4:c1:38:00:11:22=1/17".parse().unwrap();
+        assert_eq!(
+            patch,
+            DmxPatch {
+                light: "a4:c1:38:00:11:22".into(),
+                universe: 1,
+                address: 17,
+                footprint: DEFAULT_FOOTPRINT.to_vec(),
+            }
+        );
+
+        let patch: DmxPatch = "stage=2/511:power,intensity".parse().unwrap();
+        assert_eq!(patch.light, "stage");
+        assert_eq!(patch.universe, 2);
+        assert_eq!(patch.footprint, [Channel::Power, Channel::Intensity]);
+
+        for bad in [
+            "stage",
+            "stage=1",
+            "stage=0/1",
+            "stage=1/0",
+            "stage=1/513",
+            "stage=1/509",
+            "stage=1/512:power,intensity",
+            "stage=1/1:brightness",
+        ] {
+            assert!(bad.parse::<DmxPatch>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn apply_scales_channels() {
+        let patch: DmxPatch = "stage=1/3:power,intensity,cct,hue,saturation,mode,scene"
+            .parse()
+            .unwrap();
+
+        let mut state = LightState::default();
+        patch.apply(&[0, 0, 255, 255, 255, 255, 255, 100, 255], &mut state);
+        assert!(state.enabled);
+        assert_eq!(state.intensity, 100);
+        assert_eq!(state.temperature, 56);
+        assert_eq!(state.hue, HUE_RANGE - 1);
+        assert_eq!(state.saturation, 100);
+        assert_eq!(state.mode, LightMode::Hsi);
+        assert_eq!(state.scene, *Scene::ALL.last().unwrap());
+
+        patch.apply(&[255, 255, 0, 0, 0, 0, 0, 0, 0], &mut state);
+        assert!(!state.enabled);
+        assert_eq!(state.intensity, 0);
+        assert_eq!(state.temperature, 32);
+        assert_eq!(state.hue, 0);
+        assert_eq!(state.saturation, 0);
+        assert_eq!(state.mode, LightMode::Cct);
+        assert_eq!(state.scene, Scene::ALL[0]);
+
+        // A short frame leaves the channels it doesn't reach alone
+        patch.apply(&[0, 0, 255, 128], &mut state);
+        assert!(state.enabled);
+        assert_eq!(state.intensity, 50);
+        assert_eq!(state.temperature, 32);
+    }
+}
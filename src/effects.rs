@@ -0,0 +1,326 @@
+use std::{f32::consts::TAU, str::FromStr, time::Instant};
+
+use gvm::{protocol::HUE_RANGE, LightMode, LightState};
+use serde::{Deserialize, Serialize};
+
+use crate::gui::LightGuiState;
+
+const RED: u8 = 0;
+const BLUE: u8 = 55;
+
+/// Lightning and candle flicker pick a new random level this often
+const RANDOM_SLOT_SECONDS: f32 = 0.1;
+
+/// Effects generated on the host, as opposed to the light's built-in scenes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EffectKind {
+    /// Intensity swells up and down
+    Breathe,
+    /// Intensity flashes between full and dimmed
+    Strobe,
+    /// Warm light with random flicker
+    Candle,
+    /// Hue cycles around the color wheel, spread out across the lights
+    Rainbow,
+    /// Red and blue alternating between neighbouring lights
+    PoliceChase,
+    /// Dim light broken up by flashes at random times
+    Lightning,
+}
+
+impl EffectKind {
+    pub const ALL: [EffectKind; 6] = [
+        EffectKind::Breathe,
+        EffectKind::Strobe,
+        EffectKind::Candle,
+        EffectKind::Rainbow,
+        EffectKind::PoliceChase,
+        EffectKind::Lightning,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EffectKind::Breathe => "Breathe",
+            EffectKind::Strobe => "Strobe",
+            EffectKind::Candle => "Candle",
+            EffectKind::Rainbow => "Rainbow",
+            EffectKind::PoliceChase => "Police Chase",
+            EffectKind::Lightning => "Lightning",
+        }
+    }
+}
+
+impl FromStr for EffectKind {
+    type Err = String;
+
+    /// Accepts effect names regardless of case or separators, e.g.
+    /// `police-chase` and `Police Chase` are both `EffectKind::PoliceChase`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let normalize = |name: &str| {
+            name.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        };
+
+        let wanted = normalize(s);
+        Self::ALL
+            .into_iter()
+            .find(|kind| normalize(kind.name()) == wanted)
+            .ok_or_else(|| format!("unknown effect '{s}'"))
+    }
+}
+
+/// An effect and its parameters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Effect {
+    pub kind: EffectKind,
+
+    /// Cycles per second. For lightning, the average number of flashes per
+    /// second.
+    pub speed: f32,
+
+    /// How far the effect pulls the light away from its own state - Range:
+    /// [0, 100]
+    pub depth: u8,
+
+    /// Color to run the effect in, leaving the light's own mode alone if unset.
+    /// Rainbow and police chase choose their own colors. Range: [0, HUE_RANGE)
+    pub hue: Option<u8>,
+}
+
+impl Default for Effect {
+    fn default() -> Self {
+        Self {
+            kind: EffectKind::Breathe,
+            speed: 0.5,
+            depth: 50,
+            hue: None,
+        }
+    }
+}
+
+/// An effect running on one of several lights that were started together.
+/// Every light works out where it is in the effect from the shared start time,
+/// so they stay in step no matter when each one received the effect.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectRun {
+    pub effect: Effect,
+    started: Instant,
+
+    /// Position of this light among the lights running the effect, which
+    /// chases and rainbows use to offset neighbouring lights
+    index: usize,
+    count: usize,
+}
+
+impl EffectRun {
+    /// The state to send to the light `now`, given the state it would be in
+    /// without the effect
+    pub fn render(&self, base: &LightState, now: Instant) -> LightState {
+        let t = now.saturating_duration_since(self.started).as_secs_f32();
+        let speed = self.effect.speed.max(0.0);
+        let depth = self.effect.depth.min(100) as f32 / 100.0;
+        let offset = self.index as f32 / self.count.max(1) as f32;
+
+        let mut state = base.clone();
+        if let Some(hue) = self.effect.hue {
+            state.hue = hue.min(HUE_RANGE - 1);
+            state.mode = LightMode::Hsi;
+        }
+
+        // Scale the intensity down by `amount` of the depth
+        let dim = |state: &mut LightState, amount: f32| {
+            state.intensity = (base.intensity as f32 * (1.0 - depth * amount)).round() as u8;
+        };
+
+        match self.effect.kind {
+            EffectKind::Breathe => {
+                let amount = 0.5 - 0.5 * (TAU * speed * t).cos();
+                dim(&mut state, amount);
+            }
+            EffectKind::Strobe => {
+                if (speed * t).fract() >= 0.5 {
+                    dim(&mut state, 1.0);
+                }
+            }
+            EffectKind::Candle => {
+                if self.effect.hue.is_none() {
+                    state.mode = LightMode::Cct;
+                    state.temperature = 32;
+                }
+
+                // Each light flickers on its own, blending between random
+                // levels so the flicker isn't too harsh
+                let slot = t * speed.max(0.1) / RANDOM_SLOT_SECONDS;
+                let from = random(slot as u64, self.index as u64);
+                let to = random(slot as u64 + 1, self.index as u64);
+                dim(&mut state, from + (to - from) * slot.fract());
+            }
+            EffectKind::Rainbow => {
+                let phase = (speed * t + offset).fract();
+                state.hue = (phase * HUE_RANGE as f32) as u8 % HUE_RANGE;
+                state.saturation = 100;
+                state.mode = LightMode::Hsi;
+            }
+            EffectKind::PoliceChase => {
+                let step = (2.0 * speed * t) as usize + self.index;
+                state.hue = if step.is_multiple_of(2) { RED } else { BLUE };
+                state.saturation = 100;
+                state.mode = LightMode::Hsi;
+            }
+            EffectKind::Lightning => {
+                // Every light rolls the same dice so the flashes line up
+                let slot = (t / RANDOM_SLOT_SECONDS) as u64;
+                if random(slot, 0) < speed * RANDOM_SLOT_SECONDS {
+                    state.intensity = 100;
+                } else {
+                    dim(&mut state, 1.0);
+                }
+            }
+        }
+
+        state
+    }
+}
+
+/// Start an effect on the given lights together, or stop whatever effect they
+/// are running if `effect` is `None`
+pub fn start<'a>(effect: Option<&Effect>, lights: impl IntoIterator<Item = &'a mut LightGuiState>) {
+    let mut lights: Vec<_> = lights.into_iter().collect();
+    let count = lights.len();
+    let started = Instant::now();
+
+    for (index, light) in lights.iter_mut().enumerate() {
+        light.set_effect(effect.map(|effect| EffectRun {
+            effect: effect.clone(),
+            started,
+            index,
+            count,
+        }));
+    }
+}
+
+/// A repeatable random number in [0, 1) for a time slot. Lights passing the
+/// same slot and seed get the same number without having to share any state.
+fn random(slot: u64, seed: u64) -> f32 {
+    // splitmix64
+    let mut x = slot
+        .wrapping_mul(0x9e3779b97f4a7c15)
+        .wrapping_add(seed.wrapping_mul(0xbf58476d1ce4e5b9));
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+
+    (x >> 40) as f32 / (1u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn run(kind: EffectKind, started: Instant, index: usize, count: usize) -> EffectRun {
+        EffectRun {
+            effect: Effect {
+                kind,
+                speed: 2.0,
+                depth: 80,
+                ..Default::default()
+            },
+            started,
+            index,
+            count,
+        }
+    }
+
+    fn base() -> LightState {
+        LightState {
+            intensity: 90,
+            ..Default::default()
+        }
+    }
+
+    /// A few seconds of times to render the effects at
+    fn times(started: Instant) -> impl Iterator<Item = Instant> {
+        (0..500).map(move |i| started + Duration::from_millis(i * 13))
+    }
+
+    #[test]
+    fn render_is_repeatable() {
+        let started = Instant::now();
+        for kind in EffectKind::ALL {
+            let first = run(kind, started, 1, 3);
+            let second = run(kind, started, 1, 3);
+            for now in times(started) {
+                assert_eq!(first.render(&base(), now), second.render(&base(), now));
+            }
+        }
+
+        assert_eq!(random(7, 3), random(7, 3));
+        assert_ne!(random(7, 3), random(8, 3));
+    }
+
+    #[test]
+    fn render_stays_in_range() {
+        let started = Instant::now();
+        for kind in EffectKind::ALL {
+            for index in 0..3 {
+                let mut run = run(kind, started, index, 3);
+                run.effect.hue = (kind == EffectKind::Breathe).then_some(200);
+                run.effect.depth = 255;
+
+                for now in times(started) {
+                    let state = run.render(&base(), now);
+                    assert!(state.intensity <= 100, "{kind:?} {state:?}");
+                    assert!(state.hue < HUE_RANGE, "{kind:?} {state:?}");
+                    assert!(state.saturation <= 100, "{kind:?} {state:?}");
+                }
+            }
+        }
+
+        for slot in 0..1000 {
+            assert!((0.0..1.0).contains(&random(slot, 0)));
+        }
+    }
+
+    #[test]
+    fn lights_sharing_an_effect_stay_in_phase() {
+        let started = Instant::now();
+
+        for kind in [
+            EffectKind::Breathe,
+            EffectKind::Strobe,
+            EffectKind::Lightning,
+        ] {
+            let first = run(kind, started, 0, 3);
+            let last = run(kind, started, 2, 3);
+            for now in times(started) {
+                assert_eq!(first.render(&base(), now), last.render(&base(), now));
+            }
+        }
+
+        // Rainbows keep their neighbours the same distance around the wheel
+        let first = run(EffectKind::Rainbow, started, 0, 2);
+        let second = run(EffectKind::Rainbow, started, 1, 2);
+        for now in times(started) {
+            let gap = (second.render(&base(), now).hue as i32
+                - first.render(&base(), now).hue as i32)
+                .rem_euclid(HUE_RANGE as i32);
+            assert!(gap.abs_diff(HUE_RANGE as i32 / 2) <= 1, "{gap}");
+        }
+
+        // and police chases keep them in opposite colors
+        let first = run(EffectKind::PoliceChase, started, 0, 2);
+        let second = run(EffectKind::PoliceChase, started, 1, 2);
+        for now in times(started) {
+            assert_ne!(
+                first.render(&base(), now).hue,
+                second.render(&base(), now).hue
+            );
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use async_stream::stream;
+use futures::{pin_mut, Stream, StreamExt};
+use gvm::{protocol::HUE_RANGE, LightState};
+use tokio::{
+    select,
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
+
+use crate::effects::EffectRun;
+
+/// Intermediate states sent per second during a fade when the config doesn't
+/// say otherwise
+pub(crate) const DEFAULT_FADE_RATE: f32 = 10.0;
+
+/// A state for a light to move to, how long to take getting there, and the
+/// effect to run on top of it once it's there
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub state: LightState,
+    pub fade: Duration,
+    pub effect: Option<EffectRun>,
+}
+
+impl Transition {
+    pub fn new(state: LightState, fade: Duration, effect: Option<EffectRun>) -> Self {
+        Self {
+            state,
+            fade,
+            effect,
+        }
+    }
+}
+
+/// A fade in progress between two states
+struct Fade {
+    from: LightState,
+    to: LightState,
+    started: Instant,
+    duration: Duration,
+}
+
+impl Fade {
+    /// How far through the fade we are at `now`, from 0 to 1
+    fn progress(&self, now: Instant) -> f32 {
+        let elapsed = now.duration_since(self.started);
+        (elapsed.as_secs_f32() / self.duration.as_secs_f32()).min(1.0)
+    }
+}
+
+/// Pass on the most recent item of a stream at most once every `period`. The
+/// first item after a quiet spell goes straight through, and the last item is
+/// never dropped, so a light ends up in the final state however fast changes
+/// arrive.
+pub(crate) fn throttle<T>(items: impl Stream<Item = T>, period: Duration) -> impl Stream<Item = T> {
+    stream! {
+        pin_mut!(items);
+
+        let mut latest = None;
+        let mut next_send = Instant::now();
+
+        loop {
+            select! {
+                item = items.next() => match item {
+                    Some(item) => latest = Some(item),
+                    None => break,
+                },
+                _ = sleep_until(next_send), if latest.is_some() => {
+                    if let Some(item) = latest.take() {
+                        yield item;
+                    }
+                    next_send = Instant::now() + period;
+                }
+            }
+        }
+
+        if let Some(item) = latest {
+            yield item;
+        }
+    }
+}
+
+/// The state `t` of the way from `from` to `to`, where `t` is in [0, 1].
+/// Intensity, color temperature and saturation move in a straight line and hue
+/// takes the shortest way around the color wheel. Everything else switches to
+/// the target straight away, except that a light being turned off stays on
+/// until the end so that it fades out.
+pub(crate) fn interpolate(from: &LightState, to: &LightState, t: f32) -> LightState {
+    if t >= 1.0 {
+        return to.clone();
+    }
+
+    let lerp = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * t).round() as u8;
+
+    // Go the other way around if that's shorter
+    let hue_range = HUE_RANGE as i16;
+    let mut hue_delta = to.hue as i16 - from.hue as i16;
+    if hue_delta > hue_range / 2 {
+        hue_delta -= hue_range;
+    } else if hue_delta < -hue_range / 2 {
+        hue_delta += hue_range;
+    }
+    let hue = (from.hue as f32 + hue_delta as f32 * t).round() as i16;
+
+    LightState {
+        hue: hue.rem_euclid(hue_range) as u8,
+        intensity: lerp(from.intensity, to.intensity),
+        saturation: lerp(from.saturation, to.saturation),
+        temperature: lerp(from.temperature, to.temperature),
+        enabled: to.enabled || from.enabled,
+        ..to.clone()
+    }
+}
+
+/// Turn a stream of transitions into a stream of states for
+/// `Light::run`, filling in fades and effects with intermediate states at
+/// `rate` per second.
+///
+/// Steps are worked out from the time elapsed rather than counted, so when the
+/// link to the light is too slow to keep up, steps are skipped instead of
+/// queueing up behind each other. A new transition arriving mid-fade starts
+/// from wherever the fade had got to.
+pub(crate) fn fade(
+    initial_state: LightState,
+    transitions: impl Stream<Item = Transition>,
+    rate: f32,
+) -> impl Stream<Item = LightState> {
+    stream! {
+        pin_mut!(transitions);
+
+        // The state without any effect applied, which fades start from
+        let mut base = initial_state.clone();
+        let mut sent = initial_state;
+        let mut fade: Option<Fade> = None;
+        let mut effect: Option<EffectRun> = None;
+
+        // Anything outside of this is either a typo or more than any light can
+        // keep up with
+        let rate = rate.clamp(1.0, 50.0);
+        let mut ticker = interval(Duration::from_secs_f32(1.0 / rate));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            select! {
+                next = transitions.next() => {
+                    let Some(transition) = next else {
+                        break;
+                    };
+
+                    effect = transition.effect;
+                    if transition.fade.is_zero() {
+                        fade = None;
+                        base = transition.state;
+                    } else {
+                        fade = Some(Fade {
+                            from: base.clone(),
+                            to: transition.state,
+                            started: Instant::now(),
+                            duration: transition.fade,
+                        });
+                        continue;
+                    }
+                }
+                _ = ticker.tick(), if fade.is_some() || effect.is_some() => {
+                    if let Some(active) = &fade {
+                        let t = active.progress(Instant::now());
+                        base = interpolate(&active.from, &active.to, t);
+                        if t >= 1.0 {
+                            fade = None;
+                        }
+                    }
+                }
+            }
+
+            let state = match &effect {
+                Some(run) => run.render(&base, Instant::now().into_std()),
+                None => base.clone(),
+            };
+
+            // Small changes can round to the same state, which would only
+            // waste bandwidth
+            if state != sent {
+                sent = state;
+                yield sent.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use gvm::LightMode;
+
+    use super::*;
+
+    fn state(hue: u8, intensity: u8) -> LightState {
+        LightState {
+            hue,
+            intensity,
+            mode: LightMode::Hsi,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hue_takes_the_shortest_way_around() {
+        let from = state(HUE_RANGE - 3, 0);
+        let to = state(2, 0);
+
+        let hues: Vec<_> = [0.2, 0.4, 0.6, 0.8]
+            .map(|t| interpolate(&from, &to, t).hue)
+            .into();
+        assert_eq!(hues, [HUE_RANGE - 2, HUE_RANGE - 1, 0, 1]);
+
+        let hues: Vec<_> = [0.2, 0.4, 0.6, 0.8]
+            .map(|t| interpolate(&to, &from, t).hue)
+            .into();
+        assert_eq!(hues, [1, 0, HUE_RANGE - 1, HUE_RANGE - 2]);
+    }
+
+    #[test]
+    fn endpoints_are_exact() {
+        let from = state(10, 3);
+        let to = LightState {
+            saturation: 40,
+            temperature: 56,
+            ..state(70, 97)
+        };
+
+        let start = interpolate(&from, &to, 0.0);
+        assert_eq!(
+            (
+                start.hue,
+                start.intensity,
+                start.saturation,
+                start.temperature
+            ),
+            (from.hue, from.intensity, from.saturation, from.temperature)
+        );
+        assert_eq!(interpolate(&from, &to, 1.0), to);
+        assert_eq!(interpolate(&from, &to, 1.5), to);
+    }
+
+    #[test]
+    fn mode_switches_at_once_and_lights_turn_off_at_the_end() {
+        let from = state(10, 50);
+        let to = LightState {
+            mode: LightMode::Cct,
+            enabled: false,
+            ..from.clone()
+        };
+
+        let middle = interpolate(&from, &to, 0.01);
+        assert_eq!(middle.mode, LightMode::Cct);
+        assert!(middle.enabled);
+        assert!(interpolate(&from, &to, 0.99).enabled);
+        assert!(!interpolate(&from, &to, 1.0).enabled);
+
+        // but turn on straight away
+        assert!(interpolate(&to, &from, 0.0).enabled);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_passes_on_the_latest_of_a_burst() {
+        let items = async_stream::stream! {
+            for i in 1..=10 {
+                yield i;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            for i in 11..=20 {
+                yield i;
+            }
+        };
+
+        let sent: Vec<_> = throttle(items, Duration::from_millis(100)).collect().await;
+
+        // At most the first of each burst gets through early, and nothing is
+        // lost from the end of either
+        assert!(sent.len() <= 4, "{sent:?}");
+        assert!(sent.contains(&10), "{sent:?}");
+        assert_eq!(sent.last(), Some(&20));
+        assert!(sent.windows(2).all(|pair| pair[0] < pair[1]), "{sent:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fade_reaches_the_target() {
+        let from = state(0, 0);
+        let to = state(40, 100);
+        let transition = Transition::new(to.clone(), Duration::from_secs(1), None);
+
+        // Keep the transitions open, as a light's are, so the fade can finish
+        let transitions = stream::iter([transition]).chain(stream::pending());
+        let states = fade(from, transitions, 10.0);
+        pin_mut!(states);
+
+        let started = Instant::now();
+        let mut steps = Vec::new();
+        while let Some(state) = states.next().await {
+            steps.push(state.intensity);
+            if state == to {
+                break;
+            }
+        }
+
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        assert!(steps.len() > 5 && steps.len() <= 11, "{steps:?}");
+        assert!(steps.windows(2).all(|pair| pair[0] < pair[1]), "{steps:?}");
+    }
+}
@@ -1,75 +1,191 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use eframe::{IconData, NativeOptions};
-use egui::{Button, Color32, Direction, Response, Slider, Ui};
+use egui::{Button, Color32, Direction, DragValue, Response, Slider, Ui};
 use eyre::Result;
-use tokio::sync::mpsc::Sender;
+use gvm::{
+    protocol::{Scene, HUE_RANGE},
+    LightMode, LightState,
+};
+use tokio::sync::watch::{self, Sender};
+use tracing::error;
+
+use crate::{
+    circadian,
+    config::{Config, Preset},
+    effects::{self, Effect, EffectKind, EffectRun},
+    fade::Transition,
+    show::{Cue, Sequencer},
+};
 
 pub struct LightGuiState {
+    /// Stable identifier for the light (its MAC address where known) used by
+    /// anything controlling lights from outside the GUI
+    id: String,
+
+    /// Names of the groups this light belongs to
+    groups: Vec<String>,
     renaming: bool,
     name: String,
-    state: LightSettingsState,
-    tx: Sender<LightSettingsState>,
-    pending_send: bool,
-    state_needs_update: bool,
-}
-
-/// The state of the settings that we should write to the light
-#[derive(Clone, PartialEq, Debug)]
-pub struct LightSettingsState {
-    /// Range: [0, 0x53)
-    pub hue: u8,
-
-    /// Range: [0, 100]
-    pub intensity: u8,
+    state: LightState,
 
-    /// Range: [0, 100]
-    pub saturation: u8,
+    /// Only the latest transition matters, so a new one replaces any the
+    /// light hasn't picked up yet
+    tx: Sender<Transition>,
 
-    /// 100s of Kelvin - Range: [32, 56]
-    pub temperature: u8,
+    /// Host effect running on top of `state`, if any
+    effect: Option<EffectRun>,
 
-    pub mode: LightMode,
-    pub enabled: bool,
-}
-
-impl Default for LightSettingsState {
-    fn default() -> Self {
-        Self {
-            enabled: true,
-            hue: 0,
-            intensity: 10,
-            saturation: 100,
-            temperature: 32,
-            mode: LightMode::Cct,
-        }
-    }
-}
+    /// Whether the light follows the circadian curves, until it is adjusted
+    /// by hand
+    circadian: bool,
 
-#[derive(PartialEq, Debug, Clone)]
-pub enum LightMode {
-    Hsi,
-    Cct,
+    /// Woken whenever anything a daemon client can see changes: the state, the
+    /// effect or whether the light follows the circadian curves
+    changes: Sender<()>,
+    pending_send: bool,
+    state_needs_update: bool,
 }
 
 impl LightGuiState {
-    pub fn new(name: impl Into<String>, tx: Sender<LightSettingsState>) -> Self {
+    pub fn new(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        groups: Vec<String>,
+        state: LightState,
+        tx: Sender<Transition>,
+    ) -> Self {
         Self {
+            id: id.into(),
+            groups,
             name: name.into(),
             renaming: false,
-            state: LightSettingsState::default(),
+            state,
             tx,
+            effect: None,
+            circadian: false,
+            changes: watch::channel(()).0,
             pending_send: false,
             state_needs_update: false,
         }
     }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    /// Whether `target` names this light, either by id or by one of its groups
+    pub fn matches(&self, target: &str) -> bool {
+        self.id == target || self.groups.iter().any(|group| group == target)
+    }
+
+    pub fn state(&self) -> &LightState {
+        &self.state
+    }
+
+    /// Mark a state changed by a pane other than the light's own to be sent,
+    /// either now or on the next commit depending on the update mode
+    fn queue_update(&mut self, update_mode: UpdateMode) {
+        self.adjusted_by_hand();
+        if update_mode == UpdateMode::Immediate {
+            self.pending_send = true;
+        } else {
+            self.state_needs_update = true;
+        }
+    }
+
+    /// Replace the state of the light from outside the GUI and send it to the
+    /// light straight away, regardless of the GUI's update mode. Like any
+    /// change made by hand, this takes the light off the circadian curves.
+    pub fn set_state(&mut self, state: LightState) {
+        self.fade_to(state, Duration::ZERO);
+    }
+
+    /// Show a state the light was given elsewhere, without sending it back
+    #[cfg(unix)]
+    pub fn show_state(&mut self, state: LightState) {
+        self.state = state;
+        self.state_needs_update = false;
+    }
+
+    /// Like `set_state`, but the light fades to the new state over `fade`
+    pub fn fade_to(&mut self, state: LightState, fade: Duration) {
+        self.adjusted_by_hand();
+        self.fade_to_automatically(state, fade);
+    }
+
+    /// Like `fade_to`, but for changes the light is expected to make by itself,
+    /// such as following the circadian curves or the schedule, which leave it
+    /// on the curves
+    pub fn fade_to_automatically(&mut self, state: LightState, fade: Duration) {
+        self.state = state;
+        self.send(fade);
+    }
+
+    /// Take the light off the circadian curves, because it was changed by hand.
+    /// It stays off them until circadian mode is turned back on.
+    fn adjusted_by_hand(&mut self) {
+        if self.circadian {
+            self.set_circadian(false);
+        }
+    }
+
+    /// Be woken by every change to the light from now on
+    #[cfg(unix)]
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    pub fn effect(&self) -> Option<&Effect> {
+        self.effect.as_ref().map(|run| &run.effect)
+    }
+
+    /// Start running an effect on top of the light's state, or stop the
+    /// current one. Use `effects::start` to keep several lights in step.
+    pub fn set_effect(&mut self, effect: Option<EffectRun>) {
+        self.effect = effect;
+        self.send(Duration::ZERO);
+    }
+
+    pub fn circadian(&self) -> bool {
+        self.circadian
+    }
+
+    /// Turn circadian mode on or off. The light is moved onto the curves by the
+    /// next circadian update.
+    pub fn set_circadian(&mut self, circadian: bool) {
+        self.circadian = circadian;
+        self.changes.send_replace(());
+    }
+
+    fn send(&mut self, fade: Duration) {
+        let transition = Transition::new(self.state.clone(), fade, self.effect.clone());
+        self.state_needs_update = false;
+        self.pending_send = false;
+        self.tx.send_replace(transition);
+        self.changes.send_replace(());
+    }
 }
 
 /// Start the GUI, blocks the main thread. Accepts a guarded list of lights
 /// which should be initially empty but will be filled in with real data by the
 /// `bluetooth` module as it scans and finds devices.
-pub fn run(
+pub(crate) fn run(
     lights: Arc<Mutex<Vec<LightGuiState>>>,
+    config: Arc<Mutex<Config>>,
+    sequencer: Sequencer,
     demo: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let icon_png_data = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/data/app-icon.png"));
@@ -81,7 +197,7 @@ pub fn run(
     eframe::run_native(
         "GVM Director",
         native_options,
-        Box::new(move |_cc| Box::new(Gui::new(lights, demo))),
+        Box::new(move |_cc| Box::new(Gui::new(lights, config, sequencer, demo))),
     )?;
 
     Ok(())
@@ -89,12 +205,47 @@ pub fn run(
 
 struct Gui {
     lights: Arc<Mutex<Vec<LightGuiState>>>,
+    config: Arc<Mutex<Config>>,
     update_mode: UpdateMode,
     use_global: bool,
-    global_state: LightSettingsState,
+    global_state: LightState,
+    use_groups: bool,
+
+    /// Controls for each group, keyed by group name. Groups created here are
+    /// only saved once they have a member.
+    groups: BTreeMap<String, GroupPane>,
+    group_name: String,
+    use_presets: bool,
+    preset_name: String,
+
+    /// Seconds that newly saved presets fade in over
+    preset_fade: f32,
+
+    /// Light to snapshot when saving a preset, or every light if `None`
+    preset_source: Option<String>,
+    sequencer: Sequencer,
+    use_cues: bool,
+    use_effects: bool,
+    effect: Effect,
+
+    /// Light id or group that effects are started on, or every light if
+    /// `None`
+    effect_target: Option<String>,
+
+    /// Name and timings for the next cue to be recorded
+    new_cue: Cue,
     demo: bool,
 }
 
+#[derive(Default)]
+struct GroupPane {
+    state: LightState,
+
+    /// Move each member by the change in the pane's settings rather than
+    /// setting them all to the same value
+    relative: bool,
+}
+
 #[derive(PartialEq, Clone, Copy)]
 enum UpdateMode {
     Immediate,
@@ -102,12 +253,31 @@ enum UpdateMode {
 }
 
 impl Gui {
-    fn new(lights: Arc<Mutex<Vec<LightGuiState>>>, demo: bool) -> Self {
+    fn new(
+        lights: Arc<Mutex<Vec<LightGuiState>>>,
+        config: Arc<Mutex<Config>>,
+        sequencer: Sequencer,
+        demo: bool,
+    ) -> Self {
         Self {
             lights,
+            config,
             update_mode: UpdateMode::Immediate,
             use_global: false,
             global_state: Default::default(),
+            use_groups: false,
+            groups: BTreeMap::new(),
+            group_name: String::new(),
+            use_presets: false,
+            preset_name: String::new(),
+            preset_fade: 0.0,
+            preset_source: None,
+            sequencer,
+            use_cues: false,
+            use_effects: false,
+            effect: Effect::default(),
+            effect_target: None,
+            new_cue: Cue::default(),
             demo,
         }
     }
@@ -123,13 +293,288 @@ impl Gui {
             if self.global_state != previous {
                 for light in self.lights.lock().unwrap().iter_mut() {
                     light.state = self.global_state.clone();
-                    if self.update_mode == UpdateMode::Immediate {
-                        light.pending_send = true;
-                    } else {
-                        light.state_needs_update = true;
+                    light.queue_update(self.update_mode);
+                }
+            }
+        });
+    }
+
+    /// A pane for every group, each controlling only the lights in that group
+    fn draw_groups_pane(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.label("Groups");
+
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.group_name);
+                if ui
+                    .add_enabled(!self.group_name.is_empty(), Button::new("Add Group"))
+                    .clicked()
+                {
+                    let name = std::mem::take(&mut self.group_name);
+                    self.groups.entry(name).or_default();
+                }
+            });
+
+            for name in self.config.lock().unwrap().groups() {
+                self.groups.entry(name).or_default();
+            }
+
+            let mut deleted = None;
+            for (name, pane) in &mut self.groups {
+                if draw_group_pane(
+                    ui,
+                    name,
+                    pane,
+                    &mut self.lights.lock().unwrap(),
+                    self.update_mode,
+                    &self.config,
+                ) {
+                    deleted = Some(name.clone());
+                }
+            }
+
+            if let Some(name) = deleted {
+                self.groups.remove(&name);
+                for light in self.lights.lock().unwrap().iter_mut() {
+                    light.groups.retain(|group| *group != name);
+                }
+                update_config(&self.config, |config| {
+                    for light in config.lights.values_mut() {
+                        light.groups.retain(|group| *group != name);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Save and recall named snapshots of light states
+    fn draw_presets_pane(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.label("Presets");
+
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.preset_name);
+
+                let lights = self.lights.lock().unwrap();
+                let source_name = |source: &Option<String>| match source {
+                    None => String::from("All Lights"),
+                    Some(id) => lights
+                        .iter()
+                        .find(|light| light.id == *id)
+                        .map_or_else(|| id.clone(), |light| light.name.clone()),
+                };
+
+                egui::ComboBox::from_id_source("preset_source")
+                    .selected_text(source_name(&self.preset_source))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.preset_source, None, "All Lights");
+                        for light in lights.iter() {
+                            ui.selectable_value(
+                                &mut self.preset_source,
+                                Some(light.id.clone()),
+                                &light.name,
+                            );
+                        }
+                    });
+
+                ui.add(
+                    DragValue::new(&mut self.preset_fade)
+                        .clamp_range(0.0..=60.0)
+                        .speed(0.1)
+                        .prefix("Fade: ")
+                        .suffix("s"),
+                );
+
+                if ui
+                    .add_enabled(!self.preset_name.is_empty(), Button::new("Save"))
+                    .clicked()
+                {
+                    let mut preset = Preset::capture(lights.iter().filter(|light| {
+                        self.preset_source.is_none()
+                            || self.preset_source.as_deref() == Some(&light.id)
+                    }));
+                    preset.fade = self.preset_fade;
+                    let name = std::mem::take(&mut self.preset_name);
+                    update_config(&self.config, |config| {
+                        config.presets.insert(name, preset);
+                    });
+                }
+            });
+
+            let presets = self.config.lock().unwrap().presets.clone();
+            for (name, preset) in presets {
+                ui.horizontal(|ui| {
+                    if ui.button(&name).clicked() {
+                        preset.recall(&mut self.lights.lock().unwrap());
+                    }
+                    if ui.small_button("Delete").clicked() {
+                        update_config(&self.config, |config| {
+                            config.presets.remove(&name);
+                        });
+                    }
+                });
+            }
+        });
+    }
+
+    /// Run the show, and record the current light states as new cues
+    fn draw_cues_pane(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.label("Cue List");
+
+            ui.horizontal(|ui| {
+                if ui.button("GO").clicked() {
+                    self.sequencer.go();
+                }
+                if ui.button("BACK").clicked() {
+                    self.sequencer.back();
+                }
+            });
+
+            let current = self.sequencer.current();
+            let mut deleted = None;
+            for (index, cue) in self.sequencer.show().cues.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    let label = format!("{} {}", index + 1, cue.name);
+                    if ui.selectable_label(current == Some(index), label).clicked() {
+                        self.sequencer.jump(index);
+                    }
+
+                    let follow = if cue.follow { ", follow" } else { "" };
+                    ui.weak(format!(
+                        "fade {:.1}s, delay {:.1}s{follow}",
+                        cue.fade, cue.delay
+                    ));
+
+                    if ui.small_button("Delete").clicked() {
+                        deleted = Some(index);
                     }
+                });
+            }
+
+            if let Some(index) = deleted {
+                self.sequencer.edit(|show| {
+                    show.cues.remove(index);
+                });
+            }
+
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.new_cue.name);
+                ui.add(
+                    DragValue::new(&mut self.new_cue.fade)
+                        .clamp_range(0.0..=60.0)
+                        .speed(0.1)
+                        .prefix("Fade: ")
+                        .suffix("s"),
+                );
+                ui.add(
+                    DragValue::new(&mut self.new_cue.delay)
+                        .clamp_range(0.0..=60.0)
+                        .speed(0.1)
+                        .prefix("Delay: ")
+                        .suffix("s"),
+                );
+                ui.checkbox(&mut self.new_cue.follow, "Follow");
+
+                if ui
+                    .add_enabled(!self.new_cue.name.is_empty(), Button::new("Record Cue"))
+                    .clicked()
+                {
+                    let mut cue = Cue::capture(self.lights.lock().unwrap().iter());
+                    cue.name = std::mem::take(&mut self.new_cue.name);
+                    cue.fade = self.new_cue.fade;
+                    cue.delay = self.new_cue.delay;
+                    cue.follow = self.new_cue.follow;
+                    self.sequencer.edit(|show| show.cues.push(cue));
                 }
+            });
+        });
+    }
+
+    /// Start and stop host effects on a light, a group or every light
+    fn draw_effects_pane(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.label("Effects");
+
+            let mut lights = self.lights.lock().unwrap();
+
+            let mut targets: Vec<(String, String)> = self
+                .config
+                .lock()
+                .unwrap()
+                .groups()
+                .into_iter()
+                .map(|group| (group.clone(), format!("Group: {group}")))
+                .collect();
+            targets.extend(
+                lights
+                    .iter()
+                    .map(|light| (light.id.clone(), light.name.clone())),
+            );
+
+            let target_name = match &self.effect_target {
+                None => String::from("All Lights"),
+                Some(target) => targets
+                    .iter()
+                    .find(|(id, _)| id == target)
+                    .map_or_else(|| target.clone(), |(_, name)| name.clone()),
+            };
+
+            egui::ComboBox::from_label("Target")
+                .selected_text(target_name)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.effect_target, None, "All Lights");
+                    for (id, name) in targets {
+                        ui.selectable_value(&mut self.effect_target, Some(id), name);
+                    }
+                });
+
+            egui::ComboBox::from_label("Effect")
+                .selected_text(self.effect.kind.name())
+                .show_ui(ui, |ui| {
+                    for kind in EffectKind::ALL {
+                        ui.selectable_value(&mut self.effect.kind, kind, kind.name());
+                    }
+                });
+
+            ui.add(
+                Slider::new(&mut self.effect.speed, 0.05..=5.0)
+                    .logarithmic(true)
+                    .suffix("Hz")
+                    .text("Speed"),
+            );
+            slider_u8(ui, &mut self.effect.depth, |val| {
+                Slider::new(val, 0.0..=100.0).text("Depth")
+            });
+
+            let mut use_color = self.effect.hue.is_some();
+            ui.checkbox(&mut use_color, "Color");
+            if use_color {
+                let mut hue = self.effect.hue.unwrap_or(0);
+                slider_u8(ui, &mut hue, |val| {
+                    Slider::new(val, 0.0..=(HUE_RANGE - 1) as f32).text("Hue")
+                });
+                self.effect.hue = Some(hue);
+            } else {
+                self.effect.hue = None;
             }
+
+            ui.horizontal(|ui| {
+                let start = ui.button("Start").clicked();
+                let stop = ui.button("Stop").clicked();
+
+                if start || stop {
+                    let effect = start.then_some(&self.effect);
+                    let target = &self.effect_target;
+                    effects::start(
+                        effect,
+                        lights.iter_mut().filter(|light| match target {
+                            None => true,
+                            Some(target) => light.matches(target),
+                        }),
+                    );
+                }
+            });
         });
     }
 
@@ -144,12 +589,15 @@ impl Gui {
         });
         ui.group(|ui| {
             ui.checkbox(&mut self.use_global, "Use Global Setting Pane");
+            ui.checkbox(&mut self.use_groups, "Show Group Panes");
+            ui.checkbox(&mut self.use_presets, "Show Presets Pane");
+            ui.checkbox(&mut self.use_cues, "Show Cue List");
+            ui.checkbox(&mut self.use_effects, "Show Effects Pane");
         });
-        if self.update_mode == UpdateMode::Commit {
-            if ui.small_button("Commit All States").clicked() {
-                for light in self.lights.lock().unwrap().iter_mut() {
-                    light.pending_send = true;
-                }
+        if self.update_mode == UpdateMode::Commit && ui.small_button("Commit All States").clicked()
+        {
+            for light in self.lights.lock().unwrap().iter_mut() {
+                light.pending_send = true;
             }
         }
     }
@@ -171,53 +619,92 @@ impl eframe::App for Gui {
 
                     ui.vertical(|ui| {
                         for light in self.lights.lock().unwrap().iter_mut() {
-                            draw_light_group(ui, light, self.update_mode);
+                            draw_light_group(ui, light, self.update_mode, &self.config);
                         }
                     });
                     ui.vertical(|ui| {
                         if self.use_global {
                             self.draw_global_pane(ui)
                         }
+                        if self.use_groups {
+                            self.draw_groups_pane(ui)
+                        }
+                        if self.use_presets {
+                            self.draw_presets_pane(ui)
+                        }
+                        if self.use_cues {
+                            self.draw_cues_pane(ui)
+                        }
+                        if self.use_effects {
+                            self.draw_effects_pane(ui)
+                        }
                     });
                 });
             });
         });
+
+        // However a light was taken off or put back on the circadian curves,
+        // it stays that way after a restart
+        circadian::save_modes(&self.config, &self.lights.lock().unwrap());
     }
 }
 
 /// Single LED accessory. At the end of the render pass tries to determine if
 /// the state of the light was changed and sends changes to the bluetooth module
-/// if so.
-fn draw_light_group(ui: &mut Ui, light: &mut LightGuiState, update_mode: UpdateMode) {
+/// if so. Renames and saved defaults are written back to the config.
+fn draw_light_group(
+    ui: &mut Ui,
+    light: &mut LightGuiState,
+    update_mode: UpdateMode,
+    config: &Mutex<Config>,
+) {
     let previous = light.state.clone();
+    let mut circadian_changed = false;
     ui.group(|ui| {
         ui.horizontal(|ui| {
             if light.renaming {
-                if ui.text_edit_singleline(&mut light.name).lost_focus() {
-                    light.renaming = false;
-                };
-                if ui.small_button("Ok").clicked() {
+                let mut done = ui.text_edit_singleline(&mut light.name).lost_focus();
+                done |= ui.small_button("Ok").clicked();
+
+                if done {
                     light.renaming = false;
+                    update_config(config, |config| {
+                        config.light_mut(&light.id).name = Some(light.name.clone())
+                    });
                 }
             } else {
                 ui.toggle_value(&mut light.state.enabled, &light.name);
                 if ui.small_button("Rename").clicked() {
                     light.renaming = true;
                 }
+                if ui.small_button("Save as Default").clicked() {
+                    update_config(config, |config| {
+                        config.light_mut(&light.id).default_state = Some(light.state.clone())
+                    });
+                }
+                let mut circadian = light.circadian;
+                if ui.checkbox(&mut circadian, "Circadian").changed() {
+                    light.set_circadian(circadian);
+                    circadian_changed = true;
+                }
 
-                if update_mode == UpdateMode::Commit {
-                    if ui
+                if update_mode == UpdateMode::Commit
+                    && ui
                         .add_enabled(light.state_needs_update, Button::new("Commit State"))
                         .clicked()
-                    {
-                        light.pending_send = true;
-                    }
+                {
+                    light.pending_send = true;
                 }
             }
         });
 
         if light.state.enabled {
+            let before = light.state.clone();
             draw_light_settings(ui, &mut light.state);
+
+            if light.state != before {
+                light.adjusted_by_hand();
+            }
         }
     });
 
@@ -226,17 +713,84 @@ fn draw_light_group(ui: &mut Ui, light: &mut LightGuiState, update_mode: UpdateM
     }
 
     if light.pending_send || (update_mode == UpdateMode::Immediate && light.state_needs_update) {
-        light.state_needs_update = false;
-        light.pending_send = light.tx.try_send(light.state.clone()).is_err();
+        light.send(Duration::ZERO);
+    }
+
+    // Move straight onto the curves rather than waiting for the next update
+    if circadian_changed {
+        config.lock().unwrap().circadian.update(light);
+    }
+}
+
+/// Controls for a single group along with its membership. Returns true if the
+/// group should be deleted.
+fn draw_group_pane(
+    ui: &mut Ui,
+    name: &str,
+    pane: &mut GroupPane,
+    lights: &mut [LightGuiState],
+    update_mode: UpdateMode,
+    config: &Mutex<Config>,
+) -> bool {
+    let previous = pane.state.clone();
+    let mut delete = false;
+
+    ui.group(|ui| {
+        ui.horizontal(|ui| {
+            ui.toggle_value(&mut pane.state.enabled, name);
+            ui.checkbox(&mut pane.relative, "Relative");
+            delete = ui.small_button("Delete").clicked();
+        });
+
+        if pane.state.enabled {
+            draw_light_settings(ui, &mut pane.state);
+        }
+
+        ui.collapsing(format!("Members of {name}"), |ui| {
+            for light in lights.iter_mut() {
+                let mut member = light.groups.iter().any(|group| group == name);
+                if ui.checkbox(&mut member, &light.name).changed() {
+                    if member {
+                        light.groups.push(name.to_owned());
+                    } else {
+                        light.groups.retain(|group| group != name);
+                    }
+                    update_config(config, |config| {
+                        config.light_mut(&light.id).groups = light.groups.clone()
+                    });
+                }
+            }
+        });
+    });
+
+    if pane.state != previous {
+        for light in lights.iter_mut().filter(|light| light.matches(name)) {
+            if pane.relative {
+                light.state.offset(&previous, &pane.state);
+            } else {
+                light.state = pane.state.clone();
+            }
+            light.queue_update(update_mode);
+        }
+    }
+
+    delete
+}
+
+/// Apply a change to the config and save it, logging any failure to save
+fn update_config(config: &Mutex<Config>, change: impl FnOnce(&mut Config)) {
+    if let Err(e) = config.lock().unwrap().update(change) {
+        error!(error = ?e, "failed to save config");
     }
 }
 
 /// State for an LED (mode, H/S/I, CCT/I)
-fn draw_light_settings(ui: &mut Ui, state: &mut LightSettingsState) {
+fn draw_light_settings(ui: &mut Ui, state: &mut LightState) {
     ui.vertical(|ui| {
         ui.horizontal(|ui| {
             ui.radio_value(&mut state.mode, LightMode::Cct, "CCT");
             ui.radio_value(&mut state.mode, LightMode::Hsi, "HSI");
+            ui.radio_value(&mut state.mode, LightMode::Scene, "Scene");
         });
 
         ui.group(|ui| match &mut state.mode {
@@ -254,7 +808,7 @@ fn draw_light_settings(ui: &mut Ui, state: &mut LightSettingsState) {
             }
             LightMode::Hsi => {
                 slider_u8(ui, &mut state.hue, |val| {
-                    Slider::new(val, 0.0..=52.0).text("Hue")
+                    Slider::new(val, 0.0..=(HUE_RANGE - 1) as f32).text("Hue")
                 });
                 slider_u8(ui, &mut state.saturation, |val| {
                     Slider::new(val, 0.0..=100.0).text("Saturation")
@@ -263,6 +817,27 @@ fn draw_light_settings(ui: &mut Ui, state: &mut LightSettingsState) {
                     Slider::new(val, 0.0..=100.0).text("Intensity")
                 });
             }
+            LightMode::Scene => {
+                egui::ComboBox::from_label("Scene")
+                    .selected_text(state.scene.name())
+                    .show_ui(ui, |ui| {
+                        for scene in Scene::ALL {
+                            ui.selectable_value(&mut state.scene, scene, scene.name());
+                        }
+                    });
+
+                let mut interval_f32 = state.scene_interval as f32 / 10.0;
+                ui.add(
+                    egui::Slider::new(&mut interval_f32, 0.1..=5.0)
+                        .suffix("s")
+                        .text("Transition Interval"),
+                );
+                state.scene_interval = (interval_f32 * 10.0).round() as u8;
+
+                slider_u8(ui, &mut state.intensity, |val| {
+                    Slider::new(val, 0.0..=100.0).text("Intensity")
+                });
+            }
         });
     });
 }
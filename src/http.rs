@@ -0,0 +1,287 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post, put},
+    Json, Router,
+};
+use eyre::Result;
+use gvm::LightState;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{daemon::LightInfo, gui::LightGuiState};
+
+type Lights = State<Arc<Mutex<Vec<LightGuiState>>>>;
+
+#[derive(Deserialize)]
+struct PowerRequest {
+    on: bool,
+}
+
+#[derive(Deserialize)]
+struct FadeQuery {
+    /// Seconds to fade to the new state over
+    #[serde(default)]
+    fade: f32,
+}
+
+/// Serve the REST API forever. State changes go through the same channels as
+/// the GUI, so the GUI reflects anything set over HTTP. Setting state or power
+/// on a group applies it to every connected light in that group.
+pub(crate) async fn serve(addr: SocketAddr, lights: Arc<Mutex<Vec<LightGuiState>>>) -> Result<()> {
+    info!(%addr, "HTTP API listening");
+    axum::Server::bind(&addr)
+        .serve(router(lights).into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+fn router(lights: Arc<Mutex<Vec<LightGuiState>>>) -> Router {
+    Router::new()
+        .route("/lights", get(list))
+        .route("/lights/:id", get(get_light))
+        .route("/lights/:id/state", put(set_state))
+        .route("/lights/:id/power", post(power))
+        .route("/groups", get(groups))
+        .route("/groups/:name/state", put(set_state))
+        .route("/groups/:name/power", post(power))
+        .with_state(lights)
+}
+
+async fn list(State(lights): Lights) -> Json<Vec<LightInfo>> {
+    let lights = lights.lock().unwrap();
+
+    Json(lights.iter().map(LightInfo::from).collect())
+}
+
+/// Every group with connected members, and the ids of those members
+async fn groups(State(lights): Lights) -> Json<BTreeMap<String, Vec<String>>> {
+    let lights = lights.lock().unwrap();
+
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for light in lights.iter() {
+        for group in light.groups() {
+            groups
+                .entry(group.clone())
+                .or_default()
+                .push(light.id().to_owned());
+        }
+    }
+
+    Json(groups)
+}
+
+async fn get_light(
+    State(lights): Lights,
+    Path(id): Path<String>,
+) -> Result<Json<LightInfo>, StatusCode> {
+    let lights = lights.lock().unwrap();
+    let light = lights
+        .iter()
+        .find(|light| light.id() == id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(light.into()))
+}
+
+async fn set_state(
+    State(lights): Lights,
+    Path(target): Path<String>,
+    Query(query): Query<FadeQuery>,
+    Json(state): Json<LightState>,
+) -> StatusCode {
+    update_matching(&lights, &target, query.fade, |current| {
+        *current = state.clone()
+    })
+}
+
+async fn power(
+    State(lights): Lights,
+    Path(target): Path<String>,
+    Query(query): Query<FadeQuery>,
+    Json(request): Json<PowerRequest>,
+) -> StatusCode {
+    update_matching(&lights, &target, query.fade, |state| {
+        state.enabled = request.on
+    })
+}
+
+/// Change the state of every light matching a light id or group name, fading
+/// over `fade` seconds
+fn update_matching(
+    lights: &Mutex<Vec<LightGuiState>>,
+    target: &str,
+    fade: f32,
+    change: impl Fn(&mut LightState),
+) -> StatusCode {
+    let fade = Duration::try_from_secs_f32(fade).unwrap_or_default();
+    let mut status = StatusCode::NOT_FOUND;
+
+    for light in lights
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|light| light.matches(target))
+    {
+        let mut state = light.state().clone();
+        change(&mut state);
+        light.fade_to(state, fade);
+        status = StatusCode::NO_CONTENT;
+    }
+
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request},
+    };
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::fade::Transition;
+
+    fn lights() -> (
+        Arc<Mutex<Vec<LightGuiState>>>,
+        Vec<watch::Receiver<Transition>>,
+    ) {
+        let mut lights = Vec::new();
+        let mut receivers = Vec::new();
+        for (id, groups) in [("a", vec!["desk"]), ("b", vec!["desk"]), ("c", vec![])] {
+            let state = LightState::default();
+            let (tx, rx) = watch::channel(Transition::new(state.clone(), Duration::ZERO, None));
+            let groups = groups.into_iter().map(String::from).collect();
+            lights.push(LightGuiState::new(id, id.to_uppercase(), groups, state, tx));
+            receivers.push(rx);
+        }
+
+        (Arc::new(Mutex::new(lights)), receivers)
+    }
+
+    async fn send(
+        lights: &Arc<Mutex<Vec<LightGuiState>>>,
+        method: Method,
+        uri: &str,
+        body: Option<String>,
+    ) -> (StatusCode, Vec<u8>) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(body.map(Body::from).unwrap_or_else(Body::empty))
+            .unwrap();
+        let response = router(lights.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, body.to_vec())
+    }
+
+    fn dimmed(intensity: u8) -> LightState {
+        LightState {
+            intensity,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_lights() {
+        let (lights, _receivers) = lights();
+
+        let (status, body) = send(&lights, Method::GET, "/lights", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let listed: Vec<LightInfo> = serde_json::from_slice(&body).unwrap();
+        let ids: Vec<_> = listed.iter().map(|light| light.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(listed[0].name, "A");
+        assert_eq!(listed[0].groups, ["desk"]);
+
+        let (status, body) = send(&lights, Method::GET, "/lights/b", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let light: LightInfo = serde_json::from_slice(&body).unwrap();
+        assert_eq!(light.id, "b");
+
+        let (status, _) = send(&lights, Method::GET, "/lights/nope", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn sets_light_state() {
+        let (lights, receivers) = lights();
+        let body = serde_json::to_string(&dimmed(42)).unwrap();
+
+        let (status, _) = send(&lights, Method::PUT, "/lights/a/state", Some(body.clone())).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(lights.lock().unwrap()[0].state().intensity, 42);
+        assert_eq!(receivers[0].borrow().state.intensity, 42);
+        assert_eq!(
+            lights.lock().unwrap()[1].state().intensity,
+            LightState::default().intensity
+        );
+
+        let (status, _) = send(&lights, Method::PUT, "/lights/nope/state", Some(body)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn groups_fade_every_member() {
+        let (lights, receivers) = lights();
+
+        let (status, body) = send(&lights, Method::GET, "/groups", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let groups: BTreeMap<String, Vec<String>> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            groups,
+            [("desk".into(), vec!["a".into(), "b".into()])].into()
+        );
+
+        let body = serde_json::to_string(&dimmed(70)).unwrap();
+        let (status, _) = send(
+            &lights,
+            Method::PUT,
+            "/groups/desk/state?fade=2.5",
+            Some(body.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        for rx in &receivers[..2] {
+            assert_eq!(rx.borrow().state.intensity, 70);
+            assert_eq!(rx.borrow().fade, Duration::from_secs_f32(2.5));
+        }
+        assert_ne!(receivers[2].borrow().state.intensity, 70);
+
+        let (status, _) = send(
+            &lights,
+            Method::POST,
+            "/groups/desk/power?fade=1",
+            Some(r#"{"on": false}"#.into()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        for rx in &receivers[..2] {
+            assert!(!rx.borrow().state.enabled);
+            assert_eq!(rx.borrow().fade, Duration::from_secs(1));
+        }
+        assert!(receivers[2].borrow().state.enabled);
+
+        let (status, _) = send(
+            &lights,
+            Method::PUT,
+            "/groups/nope/state?fade=1",
+            Some(body),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,32 @@
+//! Control GVM LED video lights over Bluetooth LE.
+//!
+//! Lights are found with a [`Discovery`] stream, which connects to each
+//! compatible light as soon as it is found and yields a [`Light`] handle for
+//! it:
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use gvm::Discovery;
+//!
+//! # async fn example() -> eyre::Result<()> {
+//! let mut discovery = Discovery::new();
+//! while let Some(light) = discovery.next().await {
+//!     let mut light = light?;
+//!     light.set_cct(44, 60).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The `protocol` module encodes and decodes the packets the lights speak,
+//! and `simulator` provides a transport that behaves like a light without
+//! needing any hardware.
+
+pub mod bluetooth;
+pub mod light;
+pub mod protocol;
+pub mod simulator;
+pub mod transport;
+
+pub use bluetooth::{AdapterSelector, BtleTransport, Discovery};
+pub use light::{Light, LightMode, LightState, MacAddress};
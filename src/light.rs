@@ -0,0 +1,674 @@
+use std::{fmt::Debug, str::FromStr, time::Duration};
+
+use eyre::{bail, eyre, Result};
+use futures::{pin_mut, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tracing::{info, trace, warn};
+
+use crate::{
+    bluetooth::BtleTransport,
+    protocol::{
+        self, ColorTemperatureCommand, Command, HsiCommand, ModeCommand, Packable, PowerCommand,
+        Scene, SceneCommand, HUE_RANGE,
+    },
+    transport::LightTransport,
+};
+
+/// Everything that can be set on a light
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LightState {
+    /// Range: [0, HUE_RANGE)
+    pub hue: u8,
+
+    /// Range: [0, 100]
+    pub intensity: u8,
+
+    /// Range: [0, 100]
+    pub saturation: u8,
+
+    /// 100s of Kelvin - Range: [32, 56]
+    pub temperature: u8,
+
+    pub scene: Scene,
+
+    /// 100s of ms between scene transitions - Range: [1, 50]
+    pub scene_interval: u8,
+
+    pub mode: LightMode,
+    pub enabled: bool,
+}
+
+impl Default for LightState {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            hue: 0,
+            intensity: 10,
+            saturation: 100,
+            temperature: 32,
+            scene: Scene::Lightning,
+            scene_interval: 10,
+            mode: LightMode::Cct,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LightMode {
+    Hsi,
+    Cct,
+    Scene,
+}
+
+impl LightState {
+    /// Update the state to reflect a command having been sent to the light
+    pub fn apply(&mut self, command: &Command) {
+        match command {
+            Command::Power(PowerCommand::On) => self.enabled = true,
+            Command::Power(PowerCommand::Off) => self.enabled = false,
+            Command::Mode(ModeCommand::Cct) => self.mode = LightMode::Cct,
+            Command::Mode(ModeCommand::Hsi) => self.mode = LightMode::Hsi,
+            Command::Mode(ModeCommand::Scene) => self.mode = LightMode::Scene,
+            Command::Hsi(HsiCommand::Hue(hue)) => self.hue = *hue,
+            Command::Hsi(HsiCommand::Saturation(saturation)) => self.saturation = *saturation,
+            Command::Hsi(HsiCommand::Intensity(intensity)) => self.intensity = *intensity,
+            Command::ColorTemperature(ColorTemperatureCommand(temperature)) => {
+                self.temperature = *temperature
+            }
+            Command::Scene(SceneCommand::Pick(scene)) => self.scene = *scene,
+            Command::Scene(SceneCommand::Interval(interval)) => self.scene_interval = *interval,
+        }
+    }
+
+    /// Shift intensity, color temperature, hue and saturation by however much
+    /// they differ between `from` and `to`, keeping each within its range.
+    /// Anything else that differs is copied from `to`.
+    pub fn offset(&mut self, from: &Self, to: &Self) {
+        let shift = |value: u8, from: u8, to: u8, min: u8, max: u8| {
+            (value as i16 + to as i16 - from as i16).clamp(min as i16, max as i16) as u8
+        };
+
+        self.intensity = shift(self.intensity, from.intensity, to.intensity, 0, 100);
+        self.temperature = shift(self.temperature, from.temperature, to.temperature, 32, 56);
+        self.hue = shift(self.hue, from.hue, to.hue, 0, HUE_RANGE - 1);
+        self.saturation = shift(self.saturation, from.saturation, to.saturation, 0, 100);
+
+        if from.enabled != to.enabled {
+            self.enabled = to.enabled;
+        }
+        if from.mode != to.mode {
+            self.mode = to.mode.clone();
+        }
+        if from.scene != to.scene {
+            self.scene = to.scene;
+        }
+        if from.scene_interval != to.scene_interval {
+            self.scene_interval = to.scene_interval;
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum MacAddress {
+    Known([u8; 6]),
+    Unknown,
+}
+
+impl Debug for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Known(data) => {
+                for byte in &data[..5] {
+                    write!(f, "{byte:02x}:")?;
+                }
+                write!(f, "{:02x}", data[5])
+            }
+            Self::Unknown => write!(f, "Unknown"),
+        }
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = eyre::Report;
+
+    /// Parse the colon separated hex form that `Debug` produces
+    fn from_str(s: &str) -> Result<Self> {
+        let mut mac = [0; 6];
+        let mut parts = s.split(':');
+
+        for byte in &mut mac {
+            let part = parts
+                .next()
+                .ok_or_else(|| eyre!("MAC address '{s}' is too short"))?;
+            *byte = u8::from_str_radix(part, 16)
+                .map_err(|e| eyre!("invalid MAC address '{s}': {e}"))?;
+        }
+
+        if parts.next().is_some() {
+            bail!("MAC address '{s}' is too long");
+        }
+
+        Ok(Self::Known(mac))
+    }
+}
+
+/// A connected light and the transport used to talk to it. Setters only write
+/// the settings which differ from what was last written, so the first write to
+/// a light sends everything.
+pub struct Light<T = BtleTransport> {
+    transport: T,
+
+    // CoreBluetooth hides the mac address of bluetooth accessories, so the only
+    // way to get the mac address is to inspect the device properties. This is
+    // done once at connection initialization time and the full mac address is
+    // stored here.
+    mac: MacAddress,
+
+    /// The state last written to the light, if anything has been written yet
+    state: Option<LightState>,
+}
+
+impl<T: LightTransport> Light<T> {
+    /// Wrap a connected transport, asking it for the light's MAC address
+    pub async fn new(transport: T) -> Self {
+        let mut light = Self {
+            transport,
+            mac: MacAddress::Unknown,
+            state: None,
+        };
+        _ = light.discover_mac().await;
+
+        light
+    }
+
+    /// Stable identifier for the light: its MAC address where known, otherwise
+    /// the transport's id
+    pub fn id(&self) -> String {
+        match self.mac {
+            MacAddress::Unknown => self.transport.id(),
+            MacAddress::Known(_) => format!("{:?}", self.mac),
+        }
+    }
+
+    pub fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// The state last written to the light
+    pub fn state(&self) -> Option<&LightState> {
+        self.state.as_ref()
+    }
+
+    /// Write the given command to this light
+    pub async fn cmd(&self, command: impl Packable) -> Result<()> {
+        let data = command.to_wire();
+        trace!(
+            peripheral_id = %self.transport.id(),
+            peripheral_mac = ?self.mac,
+            raw = %format!("{data:02x?}"),
+            ?command,
+            "write"
+        );
+        self.transport.write(data).await?;
+
+        Ok(())
+    }
+
+    /// Move the light to `state`
+    pub async fn set_state(&mut self, state: &LightState) -> Result<()> {
+        match &self.state {
+            Some(previous) => write_state(self, state, previous).await?,
+            None => write_state_no_cmp(self, state).await?,
+        }
+        self.state = Some(state.clone());
+
+        Ok(())
+    }
+
+    pub async fn power(&mut self, on: bool) -> Result<()> {
+        self.update(|state| state.enabled = on).await
+    }
+
+    /// Switch to HSI mode with the given color
+    pub async fn set_hsi(&mut self, hue: u8, saturation: u8, intensity: u8) -> Result<()> {
+        self.update(|state| {
+            state.mode = LightMode::Hsi;
+            state.hue = hue;
+            state.saturation = saturation;
+            state.intensity = intensity;
+        })
+        .await
+    }
+
+    /// Switch to CCT mode with the given color temperature in 100s of Kelvin
+    pub async fn set_cct(&mut self, temperature: u8, intensity: u8) -> Result<()> {
+        self.update(|state| {
+            state.mode = LightMode::Cct;
+            state.temperature = temperature;
+            state.intensity = intensity;
+        })
+        .await
+    }
+
+    /// Switch to one of the light's built-in scenes
+    pub async fn set_scene(&mut self, scene: Scene) -> Result<()> {
+        self.update(|state| {
+            state.mode = LightMode::Scene;
+            state.scene = scene;
+        })
+        .await
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        self.transport.disconnect().await
+    }
+
+    /// Change part of the state, starting from the default state if nothing
+    /// has been written yet
+    async fn update(&mut self, change: impl FnOnce(&mut LightState)) -> Result<()> {
+        let mut state = self.state.clone().unwrap_or_default();
+        change(&mut state);
+
+        self.set_state(&state).await
+    }
+
+    async fn discover_mac(&mut self) -> Result<()> {
+        if let Some(mac) = self.transport.mac().await? {
+            self.mac = MacAddress::Known(mac);
+        }
+
+        Ok(())
+    }
+
+    /// Ensure the connection is healthy and attempt to reconnect if not.
+    /// Returns true if the light had to be reconnected.
+    async fn health_check(&self) -> bool {
+        if let Ok(false) = self.transport.is_connected().await {
+            warn!(
+                peripheral_id = %self.transport.id(),
+                peripheral_mac = ?self.mac,
+                "LED disconnected"
+            );
+
+            loop {
+                if let Err(e) = self.transport.reconnect().await {
+                    warn!(
+                        peripheral_id = %self.transport.id(),
+                        peripheral_mac = ?self.mac,
+                        error=?e,
+                        "Failed to reconnect"
+                    );
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+
+                    continue;
+                }
+
+                if let Ok(true) = self.transport.is_connected().await {
+                    info!(
+                        peripheral_id = %self.transport.id(),
+                        peripheral_mac = ?self.mac,
+                        "Reconnected"
+                    );
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Write a state, logging a failure rather than returning it. Whatever the
+    /// light was left in is then unknown, so the next write sends everything.
+    async fn try_set_state(&mut self, state: &LightState) {
+        if let Err(e) = self.set_state(state).await {
+            warn!(
+                peripheral_id = %self.transport.id(),
+                peripheral_mac = ?self.mac,
+                error = ?e,
+                "Failed to write state"
+            );
+            self.state = None;
+        }
+    }
+
+    /// Writes the initial state to the light, then listens forever to a stream
+    /// which yields state changes for the light and applies those state
+    /// changes, reconnecting and restoring the state whenever the connection
+    /// drops. A failed write is retried on the next health check rather than
+    /// ending the connection.
+    pub async fn run(
+        mut self,
+        initial_state: LightState,
+        state_stream: impl Stream<Item = LightState>,
+    ) -> Result<()> {
+        let mut wanted = initial_state;
+        self.state = None;
+        self.try_set_state(&wanted).await;
+
+        let mut health_interval = tokio::time::interval(Duration::from_secs(1));
+
+        pin_mut!(state_stream);
+
+        let mut notifications = self.transport.notifications().await?;
+
+        loop {
+            select! {
+                next = state_stream.next() => {
+                    wanted = match next {
+                        None => break,
+                        Some(x) => x,
+                    };
+
+                    self.try_set_state(&wanted).await;
+                }
+                _ = health_interval.tick() => {
+                    if self.mac == MacAddress::Unknown {
+                        _ = self.discover_mac().await;
+                    }
+                    // The light may have lost power, in which case it comes
+                    // back in whatever state the firmware chooses
+                    if self.health_check().await || self.state.is_none() {
+                        self.state = None;
+                        self.try_set_state(&wanted).await;
+                    }
+                }
+                next = notifications.next() => {
+                    if let Some(value) = next {
+                        let decoded = protocol::decode(&value);
+                        trace!(
+                            peripheral_id = %self.transport.id(),
+                            peripheral_mac = ?self.mac,
+                            ?value,
+                            ?decoded,
+                            "notification"
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Write every setting relevant to the state's mode, regardless of what the
+/// light was previously set to
+async fn write_state_no_cmp(led: &Light<impl LightTransport>, state: &LightState) -> Result<()> {
+    let cmd = if state.enabled {
+        PowerCommand::On
+    } else {
+        PowerCommand::Off
+    };
+    led.cmd(cmd).await?;
+
+    match state.mode {
+        LightMode::Hsi => {
+            led.cmd(HsiCommand::Hue(state.hue)).await?;
+            led.cmd(HsiCommand::Saturation(state.saturation)).await?;
+            led.cmd(HsiCommand::Intensity(state.intensity)).await?;
+            led.cmd(ModeCommand::Hsi).await?;
+        }
+        LightMode::Cct => {
+            led.cmd(ColorTemperatureCommand(state.temperature)).await?;
+            led.cmd(HsiCommand::Intensity(state.intensity)).await?;
+            led.cmd(ModeCommand::Cct).await?;
+        }
+        LightMode::Scene => {
+            led.cmd(SceneCommand::Pick(state.scene)).await?;
+            led.cmd(SceneCommand::Interval(state.scene_interval))
+                .await?;
+            led.cmd(HsiCommand::Intensity(state.intensity)).await?;
+            led.cmd(ModeCommand::Scene).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Given the current and previous state of an LED, write the commands required
+/// to update the LED's state to the new state.
+async fn write_state(
+    led: &Light<impl LightTransport>,
+    state: &LightState,
+    previous_state: &LightState,
+) -> Result<()> {
+    if state.enabled != previous_state.enabled {
+        let cmd = if state.enabled {
+            PowerCommand::On
+        } else {
+            PowerCommand::Off
+        };
+
+        led.cmd(cmd).await?;
+    }
+
+    match state.mode {
+        LightMode::Hsi => {
+            if state.hue != previous_state.hue {
+                led.cmd(HsiCommand::Hue(state.hue)).await?;
+            }
+            if state.saturation != previous_state.saturation {
+                led.cmd(HsiCommand::Saturation(state.saturation)).await?;
+            }
+            if state.intensity != previous_state.intensity {
+                led.cmd(HsiCommand::Intensity(state.intensity)).await?;
+            }
+            if state.mode != previous_state.mode {
+                led.cmd(ModeCommand::Hsi).await?;
+            }
+        }
+        LightMode::Cct => {
+            if state.temperature != previous_state.temperature {
+                led.cmd(ColorTemperatureCommand(state.temperature)).await?;
+            }
+            if state.intensity != previous_state.intensity {
+                led.cmd(HsiCommand::Intensity(state.intensity)).await?;
+            }
+            if state.mode != previous_state.mode {
+                led.cmd(ModeCommand::Cct).await?;
+            }
+        }
+        LightMode::Scene => {
+            if state.scene != previous_state.scene {
+                led.cmd(SceneCommand::Pick(state.scene)).await?;
+            }
+            if state.scene_interval != previous_state.scene_interval {
+                led.cmd(SceneCommand::Interval(state.scene_interval))
+                    .await?;
+            }
+            if state.intensity != previous_state.intensity {
+                led.cmd(HsiCommand::Intensity(state.intensity)).await?;
+            }
+            if state.mode != previous_state.mode {
+                led.cmd(ModeCommand::Scene).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    const MAC: [u8; 6] = [0xa4, 0xc1, 0x38, 0x00, 0x11, 0x22];
+
+    /// Everything written to the transport since the last call, decoded
+    fn sent(transport: &MemoryTransport) -> Vec<Command> {
+        transport
+            .take_written()
+            .iter()
+            .map(|wire| protocol::decode(wire).unwrap())
+            .collect()
+    }
+
+    fn scene_state() -> LightState {
+        LightState {
+            mode: LightMode::Scene,
+            scene: Scene::Candle,
+            scene_interval: 5,
+            intensity: 40,
+            ..LightState::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn first_write_sends_everything_for_the_mode() {
+        let transport = MemoryTransport::new("test", Some(MAC));
+        let mut light = Light::new(transport.clone()).await;
+
+        light.set_state(&scene_state()).await.unwrap();
+
+        assert_eq!(
+            sent(&transport),
+            [
+                Command::Power(PowerCommand::On),
+                Command::Scene(SceneCommand::Pick(Scene::Candle)),
+                Command::Scene(SceneCommand::Interval(5)),
+                Command::Hsi(HsiCommand::Intensity(40)),
+                Command::Mode(ModeCommand::Scene),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn later_writes_only_send_changes() {
+        let transport = MemoryTransport::new("test", Some(MAC));
+        let mut light = Light::new(transport.clone()).await;
+
+        let mut state = LightState {
+            mode: LightMode::Hsi,
+            ..LightState::default()
+        };
+        light.set_state(&state).await.unwrap();
+        transport.take_written();
+
+        light.set_state(&state).await.unwrap();
+        assert_eq!(sent(&transport), []);
+
+        state.hue = 20;
+        light.set_state(&state).await.unwrap();
+        assert_eq!(sent(&transport), [Command::Hsi(HsiCommand::Hue(20))]);
+
+        state.enabled = false;
+        light.set_state(&state).await.unwrap();
+        assert_eq!(sent(&transport), [Command::Power(PowerCommand::Off)]);
+
+        // Settings that don't apply to the new mode aren't sent
+        state.mode = LightMode::Cct;
+        state.hue = 30;
+        light.set_state(&state).await.unwrap();
+        assert_eq!(sent(&transport), [Command::Mode(ModeCommand::Cct)]);
+    }
+
+    #[tokio::test]
+    async fn scene_changes_are_diffed() {
+        let transport = MemoryTransport::new("test", Some(MAC));
+        let mut light = Light::new(transport.clone()).await;
+
+        let mut state = LightState {
+            mode: LightMode::Cct,
+            ..scene_state()
+        };
+        light.set_state(&state).await.unwrap();
+        transport.take_written();
+
+        state.mode = LightMode::Scene;
+        light.set_state(&state).await.unwrap();
+        assert_eq!(sent(&transport), [Command::Mode(ModeCommand::Scene)]);
+
+        state.scene = Scene::Disco;
+        light.set_state(&state).await.unwrap();
+        assert_eq!(
+            sent(&transport),
+            [Command::Scene(SceneCommand::Pick(Scene::Disco))]
+        );
+
+        state.scene_interval = 20;
+        light.set_state(&state).await.unwrap();
+        assert_eq!(
+            sent(&transport),
+            [Command::Scene(SceneCommand::Interval(20))]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_restores_state_after_reconnecting() {
+        let transport = MemoryTransport::new("test", Some(MAC));
+        let light = Light::new(transport.clone()).await;
+
+        let (tx, rx) = unbounded_channel();
+        let task = tokio::spawn(light.run(LightState::default(), UnboundedReceiverStream::new(rx)));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        transport.take_written();
+
+        let state = scene_state();
+        tx.send(state.clone()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!sent(&transport).is_empty());
+
+        // The light drops off and ignores the first reconnection attempt
+        transport.set_connected(false);
+        transport.set_reject_reconnect(true);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(sent(&transport), []);
+
+        transport.set_reject_reconnect(false);
+        tokio::time::sleep(Duration::from_secs(6)).await;
+
+        // Everything is re-asserted, since the light may have lost power
+        let expected = MemoryTransport::new("expected", None);
+        let mut fresh = Light::new(expected.clone()).await;
+        fresh.set_state(&state).await.unwrap();
+        assert_eq!(sent(&transport), sent(&expected));
+
+        drop(tx);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_retries_failed_writes() {
+        let transport = MemoryTransport::new("test", Some(MAC));
+        let light = Light::new(transport.clone()).await;
+
+        let (tx, rx) = unbounded_channel();
+        let task = tokio::spawn(light.run(LightState::default(), UnboundedReceiverStream::new(rx)));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        transport.take_written();
+
+        // The write fails while the light is away, which mustn't end the
+        // connection
+        transport.set_connected(false);
+        transport.set_reject_reconnect(true);
+        let state = scene_state();
+        tx.send(state.clone()).unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(!task.is_finished());
+        assert_eq!(sent(&transport), []);
+
+        transport.set_reject_reconnect(false);
+        tokio::time::sleep(Duration::from_secs(6)).await;
+
+        let expected = MemoryTransport::new("expected", None);
+        let mut fresh = Light::new(expected.clone()).await;
+        fresh.set_state(&state).await.unwrap();
+        assert_eq!(sent(&transport), sent(&expected));
+
+        drop(tx);
+        task.await.unwrap().unwrap();
+    }
+}
@@ -1,23 +1,117 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+};
 
-use clap::Parser;
-use tracing::{metadata::LevelFilter, warn};
+use clap::{Parser, Subcommand};
+use tracing::{info, metadata::LevelFilter, warn};
 use tracing_subscriber::EnvFilter;
 
-mod bluetooth;
+mod audio;
+mod circadian;
+mod cli;
+mod config;
+mod connections;
+mod daemon;
+mod dmx;
+mod effects;
+mod fade;
 mod gui;
-mod protocol;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod osc;
+mod schedule;
+mod show;
 
 /// GUI for controlling GVM studio LEDs
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    /// Fake the bluetooth stack for testing the UI
-    #[arg(long)]
+    /// Fake the bluetooth stack with simulated lights for testing the UI
+    #[arg(long, global = true)]
     demo: bool,
+
+    /// Unix socket of the daemon. Commands use a running daemon at the default
+    /// location automatically.
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+
+    /// Bluetooth adapter to scan on, by index, name (e.g. `hci0`) or address as
+    /// shown by `list-adapters`. May be given multiple times to scan on several
+    /// adapters at once and spread the lights across them. Defaults to the
+    /// first adapter.
+    #[arg(long, global = true)]
+    adapter: Vec<gvm::AdapterSelector>,
+
+    /// Config file with light names and defaults. Defaults to
+    /// `gvm-led-control/config.toml` in the user's config directory.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Serve the REST API on this address, e.g. `127.0.0.1:8080`
+    #[cfg(feature = "http")]
+    #[arg(long, global = true)]
+    http: Option<std::net::SocketAddr>,
+
+    /// Publish lights to Home Assistant through the MQTT broker at `host:port`
+    #[cfg(feature = "mqtt")]
+    #[arg(long, global = true)]
+    mqtt: Option<String>,
+
+    /// Drive a light or group from sACN / Art-Net, as
+    /// `<light>=<universe>/<address>[:<channel>,...]`. Channels are power,
+    /// intensity, cct, hue, saturation, mode and scene, defaulting to
+    /// intensity,cct,hue,saturation,mode. Universes count from 1 as in sACN,
+    /// so Art-Net port address 0 is universe 1. May be given multiple times.
+    #[arg(long, global = true)]
+    dmx_patch: Vec<dmx::DmxPatch>,
+
+    /// Listen for OSC messages on this address, e.g. `0.0.0.0:9000`
+    #[arg(long, global = true)]
+    osc: Option<std::net::SocketAddr>,
+
+    /// Pulse lights with music from a WAV file, or from raw signed 16-bit
+    /// little endian PCM on stdin if `-`
+    #[arg(long, global = true)]
+    audio: Option<audio::AudioSource>,
+
+    /// Light or group driven by `--audio`, defaulting to every light. May be
+    /// given multiple times.
+    #[arg(long, global = true)]
+    audio_light: Vec<String>,
+
+    /// Sample rate of raw PCM on stdin
+    #[arg(long, global = true, default_value_t = 44100, value_parser = clap::value_parser!(u32).range(1..))]
+    pcm_rate: u32,
+
+    /// Number of interleaved channels of raw PCM on stdin
+    #[arg(long, global = true, default_value_t = 2, value_parser = clap::value_parser!(u16).range(1..))]
+    pcm_channels: u16,
+
+    /// Show file holding the cue list. Defaults to `show.toml` beside the
+    /// config.
+    #[arg(long, global = true)]
+    show: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Keep the lights connected without a GUI, and share them with other
+    /// processes over a unix socket
+    #[cfg(unix)]
+    Daemon,
+
+    #[command(flatten)]
+    Cli(cli::CliCommand),
 }
 
-fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+fn main() -> std::result::Result<ExitCode, Box<dyn std::error::Error>> {
     tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::builder()
@@ -28,18 +122,143 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
+    let rt = tokio::runtime::Runtime::new()?;
+
+    let config_path = match args.config {
+        Some(path) => path,
+        None => config::Config::default_path()?,
+    };
+    let config = config::Config::load(&config_path)?;
+    let show_path = args
+        .show
+        .unwrap_or_else(|| config_path.with_file_name("show.toml"));
+
+    if let Some(Command::Cli(command)) = args.command {
+        return Ok(rt.block_on(cli::run(
+            command,
+            args.demo,
+            args.socket,
+            args.adapter,
+            config,
+            show_path,
+        ))?);
+    }
+
+    let config = Arc::new(Mutex::new(config));
+    let last_states = Arc::new(Mutex::new(config::LastStates::load(&config_path)?));
+    rt.spawn(config::save_last_states(last_states.clone()));
+
     let lights = Arc::new(Mutex::new(Vec::new()));
 
-    let rt = tokio::runtime::Runtime::new()?;
+    // A GUI started while a daemon is running controls the daemon's lights,
+    // rather than competing with it for the connections. The daemon also runs
+    // the schedule and circadian curves for them.
+    let daemon_socket = match args.command {
+        None => daemon::find_socket(args.socket.clone()).filter(|path| daemon::is_listening(path)),
+        Some(_) => None,
+    };
+
+    if daemon_socket.is_none() {
+        rt.spawn(schedule::run(config.clone(), lights.clone()));
+        rt.spawn(circadian::run(config.clone(), lights.clone()));
+    }
+
+    let (sequencer, sequencer_task) = show::Sequencer::new(show_path, lights.clone())?;
+    rt.spawn(sequencer_task);
 
-    if args.demo {
+    if let Some(socket) = daemon_socket {
+        info!(path = %socket.display(), "controlling the lights of a running daemon");
+        let lights = lights.clone();
+        rt.spawn(async move {
+            if let Err(e) = daemon::mirror(socket, lights).await {
+                tracing::error!(error = ?e, "lost the daemon");
+            }
+        });
+    } else if args.demo {
         warn!("--demo found on CLI, not running with a real bluetooth stack.");
-        rt.spawn(bluetooth::scan_and_spawn_demo_mode(lights.clone()));
+        rt.spawn(connections::scan_and_spawn_demo_mode(
+            lights.clone(),
+            config.clone(),
+            last_states.clone(),
+        ));
     } else {
-        rt.spawn(bluetooth::scan_and_spawn(lights.clone()));
+        rt.spawn(connections::scan_and_spawn(
+            lights.clone(),
+            config.clone(),
+            last_states.clone(),
+            args.adapter,
+        ));
+    }
+
+    #[cfg(feature = "http")]
+    if let Some(addr) = args.http {
+        let lights = lights.clone();
+        rt.spawn(async move {
+            if let Err(e) = http::serve(addr, lights).await {
+                tracing::error!(error = ?e, "HTTP API failed");
+            }
+        });
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let Some(broker) = args.mqtt {
+        let lights = lights.clone();
+        rt.spawn(async move {
+            if let Err(e) = mqtt::run(&broker, lights).await {
+                tracing::error!(error = ?e, "MQTT integration failed");
+            }
+        });
+    }
+
+    if !args.dmx_patch.is_empty() {
+        let lights = lights.clone();
+        rt.spawn(async move {
+            if let Err(e) = dmx::run(args.dmx_patch, lights).await {
+                tracing::error!(error = ?e, "DMX input failed");
+            }
+        });
+    }
+
+    if let Some(source) = args.audio {
+        let lights = lights.clone();
+        let pcm = audio::PcmFormat {
+            sample_rate: args.pcm_rate,
+            channels: args.pcm_channels,
+        };
+        let rate = config
+            .lock()
+            .unwrap()
+            .fade_rate
+            .unwrap_or(fade::DEFAULT_FADE_RATE);
+        rt.spawn(async move {
+            if let Err(e) = audio::run(source, pcm, args.audio_light, rate, lights).await {
+                tracing::error!(error = ?e, "audio-reactive mode failed");
+            }
+        });
+    }
+
+    if let Some(addr) = args.osc {
+        let lights = lights.clone();
+        rt.spawn(async move {
+            if let Err(e) = osc::run(addr, lights).await {
+                tracing::error!(error = ?e, "OSC server failed");
+            }
+        });
     }
 
-    gui::run(lights, args.demo)?;
+    #[cfg(unix)]
+    if let Some(Command::Daemon) = args.command {
+        let socket = args.socket.unwrap_or_else(daemon::default_socket_path);
+        rt.block_on(daemon::serve(&socket, lights, config, sequencer))?;
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    gui::run(lights, config, sequencer, args.demo)?;
+
+    if let Err(e) = last_states.lock().unwrap().save() {
+        warn!(error = ?e, "failed to save last light states");
+    }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
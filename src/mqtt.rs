@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hasher, RandomState},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use eyre::{eyre, Result};
+use gvm::{protocol::HUE_RANGE, LightMode, LightState};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::gui::LightGuiState;
+
+/// Topics for each light live under `<TOPIC_PREFIX>/<object id>/`
+const TOPIC_PREFIX: &str = "gvm";
+const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// How often the light list is checked for new lights and state changes
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Bounds of `ColorTemperatureCommand` (3200K - 5600K) expressed in mireds
+const MIN_MIREDS: u16 = 179;
+const MAX_MIREDS: u16 = 313;
+
+/// Payload of Home Assistant's JSON schema for MQTT lights, used for both
+/// commands and state
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct HaLightState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brightness: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color_temp: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<HsColor>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HsColor {
+    h: f32,
+    s: f32,
+}
+
+/// Connect to the broker at `host:port`, publish every light as a Home
+/// Assistant entity, and keep state flowing in both directions forever
+pub(crate) async fn run(broker: &str, lights: Arc<Mutex<Vec<LightGuiState>>>) -> Result<()> {
+    let (host, port) = broker
+        .rsplit_once(':')
+        .ok_or_else(|| eyre!("MQTT broker must be given as host:port"))?;
+    let port = port.parse()?;
+
+    // Brokers disconnect whichever client was connected first when a second
+    // one uses the same id, so every instance needs its own
+    let suffix = RandomState::new().build_hasher().finish() as u32;
+    let mut options = MqttOptions::new(format!("gvm-led-control-{suffix:08x}"), host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+
+    let (client, eventloop) = AsyncClient::new(options, 10);
+
+    // Set on every (re)connection. The broker forgets subscriptions along with
+    // the session, and may have lost retained discovery configs too.
+    let connected = Arc::new(AtomicBool::new(false));
+    tokio::spawn(handle_commands(
+        eventloop,
+        client.clone(),
+        connected.clone(),
+        lights.clone(),
+    ));
+
+    // Everything published for a light, keyed by object id. Lights missing from
+    // here still need their discovery config published.
+    let mut published: HashMap<String, LightState> = HashMap::new();
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if connected.swap(false, Ordering::SeqCst) {
+            published.clear();
+        }
+
+        let snapshot: Vec<_> = lights
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|light| {
+                (
+                    object_id(light.id()),
+                    light.name().to_owned(),
+                    light.state().clone(),
+                )
+            })
+            .collect();
+
+        for (object_id, name, state) in snapshot {
+            match published.get(&object_id) {
+                Some(previous) if *previous == state => continue,
+                Some(_) => {}
+                None => {
+                    let config = discovery_config(&object_id, &name);
+                    let published = client
+                        .publish(
+                            format!("{DISCOVERY_PREFIX}/light/{object_id}/config"),
+                            QoS::AtLeastOnce,
+                            true,
+                            serde_json::to_vec(&config)?,
+                        )
+                        .await;
+
+                    // Left unpublished so it is tried again on the next tick
+                    if let Err(e) = published {
+                        warn!(error = ?e, object_id, "failed to publish discovery config");
+                        continue;
+                    }
+                    info!(object_id, "published Home Assistant discovery config");
+                }
+            }
+
+            let sent = client
+                .publish(
+                    format!("{TOPIC_PREFIX}/{object_id}/state"),
+                    QoS::AtLeastOnce,
+                    true,
+                    serde_json::to_vec(&to_home_assistant(&state))?,
+                )
+                .await;
+
+            match sent {
+                Ok(()) => {
+                    published.insert(object_id, state);
+                }
+                Err(e) => warn!(error = ?e, object_id, "failed to publish light state"),
+            }
+        }
+    }
+}
+
+/// Apply commands sent by Home Assistant to the matching lights, subscribing
+/// to them again and flagging `connected` whenever the broker connection is
+/// (re)established
+async fn handle_commands(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    connected: Arc<AtomicBool>,
+    lights: Arc<Mutex<Vec<LightGuiState>>>,
+) {
+    loop {
+        let publish = match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::Publish(publish))) => publish,
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("connected to MQTT broker");
+
+                // Awaiting here would deadlock if the request queue were full,
+                // since only this loop drains it
+                if let Err(e) =
+                    client.try_subscribe(format!("{TOPIC_PREFIX}/+/set"), QoS::AtLeastOnce)
+                {
+                    warn!(error = ?e, "failed to subscribe to MQTT commands");
+                }
+                connected.store(true, Ordering::SeqCst);
+                continue;
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(error = ?e, "MQTT connection error");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        let Some(object) = publish
+            .topic
+            .strip_prefix(&format!("{TOPIC_PREFIX}/"))
+            .and_then(|topic| topic.strip_suffix("/set"))
+        else {
+            continue;
+        };
+
+        let command: HaLightState = match serde_json::from_slice(&publish.payload) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(error = %e, topic = %publish.topic, "invalid MQTT command");
+                continue;
+            }
+        };
+
+        // Groups aren't published to Home Assistant, but commands may still
+        // be sent to `gvm/<group>/set` by hand
+        let mut lights = lights.lock().unwrap();
+        for light in lights
+            .iter_mut()
+            .filter(|light| object_id(light.id()) == object || light.matches(object))
+        {
+            let mut state = light.state().clone();
+            apply_home_assistant(&mut state, &command);
+            light.set_state(state);
+        }
+    }
+}
+
+/// Light ids are MAC addresses, which aren't allowed in Home Assistant object
+/// ids
+fn object_id(id: &str) -> String {
+    let id: String = id.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    format!("gvm_{id}")
+}
+
+fn discovery_config(object_id: &str, name: &str) -> serde_json::Value {
+    json!({
+        "name": name,
+        "unique_id": object_id,
+        "object_id": object_id,
+        "schema": "json",
+        "command_topic": format!("{TOPIC_PREFIX}/{object_id}/set"),
+        "state_topic": format!("{TOPIC_PREFIX}/{object_id}/state"),
+        "brightness": true,
+        "brightness_scale": 100,
+        "supported_color_modes": ["color_temp", "hs"],
+        "min_mireds": MIN_MIREDS,
+        "max_mireds": MAX_MIREDS,
+        "device": {
+            "identifiers": [object_id],
+            "name": name,
+            "manufacturer": "GVM",
+        },
+    })
+}
+
+fn to_home_assistant(state: &LightState) -> HaLightState {
+    let mut ha = HaLightState {
+        state: Some(String::from(if state.enabled { "ON" } else { "OFF" })),
+        brightness: Some(state.intensity),
+        ..Default::default()
+    };
+
+    match state.mode {
+        LightMode::Hsi => {
+            ha.color_mode = Some(String::from("hs"));
+            ha.color = Some(HsColor {
+                h: state.hue as f32 / HUE_RANGE as f32 * 360.0,
+                s: state.saturation as f32,
+            });
+        }
+        // Home Assistant has no notion of the built-in scenes, so they are
+        // reported as the color temperature the light would return to
+        LightMode::Cct | LightMode::Scene => {
+            ha.color_mode = Some(String::from("color_temp"));
+            let kelvin = state.temperature.clamp(32, 56) as f32 * 100.0;
+            let mireds = (1_000_000.0 / kelvin).round() as u16;
+            ha.color_temp = Some(mireds.clamp(MIN_MIREDS, MAX_MIREDS));
+        }
+    }
+
+    ha
+}
+
+fn apply_home_assistant(state: &mut LightState, command: &HaLightState) {
+    if let Some(power) = &command.state {
+        state.enabled = power == "ON";
+    }
+
+    if let Some(brightness) = command.brightness {
+        state.intensity = brightness.min(100);
+    }
+
+    if let Some(mireds) = command.color_temp {
+        let mireds = mireds.clamp(MIN_MIREDS, MAX_MIREDS) as f32;
+        state.temperature = (1_000_000.0 / mireds / 100.0).round().clamp(32.0, 56.0) as u8;
+        state.mode = LightMode::Cct;
+    }
+
+    if let Some(color) = &command.color {
+        let hue = (color.h.rem_euclid(360.0) / 360.0 * HUE_RANGE as f32).round() as u8;
+        state.hue = hue % HUE_RANGE;
+        state.saturation = color.s.clamp(0.0, 100.0).round() as u8;
+        state.mode = LightMode::Hsi;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a state to Home Assistant and back as JSON
+    fn round_trip(state: &LightState) -> LightState {
+        let json = serde_json::to_string(&to_home_assistant(state)).unwrap();
+        let command: HaLightState = serde_json::from_str(&json).unwrap();
+
+        let mut received = LightState::default();
+        apply_home_assistant(&mut received, &command);
+        received
+    }
+
+    #[test]
+    fn cct_round_trips() {
+        for temperature in 32..=56 {
+            let state = LightState {
+                temperature,
+                intensity: 37,
+                mode: LightMode::Cct,
+                ..Default::default()
+            };
+
+            let ha = to_home_assistant(&state);
+            assert_eq!(ha.color_mode.as_deref(), Some("color_temp"));
+            assert!((MIN_MIREDS..=MAX_MIREDS).contains(&ha.color_temp.unwrap()));
+            assert_eq!(round_trip(&state), state);
+        }
+
+        let warmest = to_home_assistant(&LightState {
+            temperature: 32,
+            ..Default::default()
+        });
+        assert_eq!(warmest.color_temp, Some(MAX_MIREDS));
+    }
+
+    #[test]
+    fn hs_round_trips() {
+        for hue in 0..HUE_RANGE {
+            let state = LightState {
+                hue,
+                saturation: 64,
+                mode: LightMode::Hsi,
+                ..Default::default()
+            };
+
+            assert_eq!(to_home_assistant(&state).color_mode.as_deref(), Some("hs"));
+            assert_eq!(round_trip(&state), state);
+        }
+    }
+
+    #[test]
+    fn brightness_and_power_round_trip() {
+        for intensity in [0, 1, 50, 100] {
+            for enabled in [false, true] {
+                let state = LightState {
+                    intensity,
+                    enabled,
+                    ..Default::default()
+                };
+                assert_eq!(round_trip(&state), state);
+            }
+        }
+    }
+
+    #[test]
+    fn commands_are_clamped() {
+        let command: HaLightState =
+            serde_json::from_str(r#"{"state": "ON", "brightness": 255, "color_temp": 500}"#)
+                .unwrap();
+
+        let mut state = LightState::default();
+        apply_home_assistant(&mut state, &command);
+        assert_eq!(state.intensity, 100);
+        assert_eq!(state.temperature, 32);
+        assert_eq!(state.mode, LightMode::Cct);
+
+        let command: HaLightState =
+            serde_json::from_str(r#"{"color": {"h": 359.9, "s": 150}}"#).unwrap();
+        apply_home_assistant(&mut state, &command);
+        assert_eq!(state.hue, 0);
+        assert_eq!(state.saturation, 100);
+        assert_eq!(state.mode, LightMode::Hsi);
+    }
+
+    #[test]
+    fn scenes_are_reported_as_color_temperature() {
+        let state = LightState {
+            mode: LightMode::Scene,
+            temperature: 50,
+            ..Default::default()
+        };
+        let ha = to_home_assistant(&state);
+
+        assert_eq!(ha.color_mode.as_deref(), Some("color_temp"));
+        assert_eq!(ha.color_temp, Some(200));
+        assert!(ha.color.is_none());
+    }
+}
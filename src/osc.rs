@@ -0,0 +1,580 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use eyre::Result;
+use gvm::{
+    protocol::{Scene, HUE_RANGE},
+    LightMode, LightState,
+};
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+use crate::{
+    effects::{self, Effect},
+    gui::LightGuiState,
+};
+
+/// Every address handled here starts with this
+const ADDRESS_PREFIX: &str = "/gvm/";
+
+/// Addresses with this in place of a light id apply to every light
+const ALL_LIGHTS: &str = "all";
+
+/// A single argument of an OSC message
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+impl Arg {
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            Self::Int(x) => Some(*x as f32),
+            Self::Float(x) => Some(*x),
+            Self::Bool(x) => Some(if *x { 1.0 } else { 0.0 }),
+            Self::String(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Message {
+    address: String,
+    args: Vec<Arg>,
+}
+
+/// Listen for OSC messages forever. Supported addresses, where `<light>` is a
+/// light id, a group name or `all`:
+///
+/// - `/gvm/<light>/power <0|1>`
+/// - `/gvm/<light>/intensity <percent>`
+/// - `/gvm/<light>/kelvin <3200-5600>`
+/// - `/gvm/<light>/hsi <hue 0-82> <saturation> <intensity>`
+/// - `/gvm/<light>/scene <name or 1-8>`
+/// - `/gvm/<light>/effect <name or stop> [speed] [depth]` - lights matched by
+///   the same message run the effect in step
+/// - `/gvm/<light>/state` - replies to the sender with `/gvm/<id>/state` for
+///   each matching light
+pub(crate) async fn run(addr: SocketAddr, lights: Arc<Mutex<Vec<LightGuiState>>>) -> Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    info!(%addr, "OSC listening");
+
+    let mut buf = [0; 4096];
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+
+        let messages = match parse_packet(&buf[..len]) {
+            Some(x) => x,
+            None => {
+                warn!(%from, "invalid OSC packet");
+                continue;
+            }
+        };
+
+        for message in messages {
+            let replies = handle_message(&message, &lights);
+            for reply in replies {
+                socket.send_to(&encode_message(&reply), from).await?;
+            }
+        }
+    }
+}
+
+/// Apply a message to the matching lights, returning any replies to send back
+fn handle_message(message: &Message, lights: &Mutex<Vec<LightGuiState>>) -> Vec<Message> {
+    let Some((target, method)) = message
+        .address
+        .strip_prefix(ADDRESS_PREFIX)
+        .and_then(|rest| rest.rsplit_once('/'))
+    else {
+        return Vec::new();
+    };
+
+    let mut replies = Vec::new();
+    let mut lights = lights.lock().unwrap();
+
+    let matching = lights
+        .iter_mut()
+        .filter(|light| target == ALL_LIGHTS || light.matches(target));
+
+    if method == "effect" {
+        match parse_effect(&message.args) {
+            Some(effect) => effects::start(effect.as_ref(), matching),
+            None => {
+                warn!(address = %message.address, args = ?message.args, "unsupported OSC message")
+            }
+        }
+        return replies;
+    }
+
+    for light in matching {
+        if method == "state" {
+            replies.push(state_message(light.id(), light.state()));
+            continue;
+        }
+
+        let mut state = light.state().clone();
+        if !apply(method, &message.args, &mut state) {
+            warn!(address = %message.address, args = ?message.args, "unsupported OSC message");
+            break;
+        }
+
+        if state != *light.state() {
+            light.set_state(state);
+        }
+    }
+
+    replies
+}
+
+/// Update a light's state for a single OSC method. Returns false if the method
+/// or its arguments aren't understood.
+fn apply(method: &str, args: &[Arg], state: &mut LightState) -> bool {
+    let number = |index: usize| args.get(index).and_then(Arg::as_f32);
+    let percent = |index: usize| number(index).map(|x| x.clamp(0.0, 100.0).round() as u8);
+
+    match method {
+        "power" => match number(0) {
+            Some(x) => state.enabled = x != 0.0,
+            None => return false,
+        },
+        "intensity" => match percent(0) {
+            Some(x) => state.intensity = x,
+            None => return false,
+        },
+        "kelvin" => match number(0) {
+            Some(kelvin) => {
+                state.temperature = (kelvin.clamp(3200.0, 5600.0) / 100.0).round() as u8;
+                state.mode = LightMode::Cct;
+            }
+            None => return false,
+        },
+        "hsi" => match (number(0), percent(1), percent(2)) {
+            (Some(hue), Some(saturation), Some(intensity)) => {
+                state.hue = hue.clamp(0.0, (HUE_RANGE - 1) as f32).round() as u8;
+                state.saturation = saturation;
+                state.intensity = intensity;
+                state.mode = LightMode::Hsi;
+            }
+            _ => return false,
+        },
+        "scene" => {
+            let scene = match args.first() {
+                Some(Arg::String(name)) => name.parse().ok(),
+                Some(arg) => arg.as_f32().and_then(|id| Scene::from_id(id as u8)),
+                None => None,
+            };
+
+            match scene {
+                Some(scene) => {
+                    state.scene = scene;
+                    state.mode = LightMode::Scene;
+                }
+                None => return false,
+            }
+        }
+        _ => return false,
+    }
+
+    true
+}
+
+/// Parse the arguments of an `effect` message. `Some(None)` stops any running
+/// effect.
+fn parse_effect(args: &[Arg]) -> Option<Option<Effect>> {
+    let Some(Arg::String(name)) = args.first() else {
+        return None;
+    };
+
+    if name == "stop" {
+        return Some(None);
+    }
+
+    let mut effect = Effect {
+        kind: name.parse().ok()?,
+        ..Default::default()
+    };
+    if let Some(speed) = args.get(1).and_then(Arg::as_f32) {
+        effect.speed = speed.max(0.0);
+    }
+    if let Some(depth) = args.get(2).and_then(Arg::as_f32) {
+        effect.depth = depth.clamp(0.0, 100.0).round() as u8;
+    }
+
+    Some(Some(effect))
+}
+
+fn state_message(id: &str, state: &LightState) -> Message {
+    let mode = match state.mode {
+        LightMode::Cct => "cct",
+        LightMode::Hsi => "hsi",
+        LightMode::Scene => "scene",
+    };
+
+    Message {
+        address: format!("{ADDRESS_PREFIX}{id}/state"),
+        args: vec![
+            Arg::Bool(state.enabled),
+            Arg::String(mode.to_owned()),
+            Arg::Int(state.intensity as i32),
+            Arg::Int(state.temperature as i32 * 100),
+            Arg::Int(state.hue as i32),
+            Arg::Int(state.saturation as i32),
+            Arg::String(state.scene.name().to_owned()),
+        ],
+    }
+}
+
+/// Parse a message or bundle into the messages it contains. Bundle time tags
+/// are ignored, everything is applied immediately.
+fn parse_packet(data: &[u8]) -> Option<Vec<Message>> {
+    if let Some(mut rest) = data.strip_prefix(b"#bundle\0") {
+        // Skip the time tag
+        rest = rest.get(8..)?;
+
+        let mut messages = Vec::new();
+        while !rest.is_empty() {
+            // Sizes are signed on the wire, and a negative one is malformed
+            let size = i32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+            let end = usize::try_from(size).ok()?.checked_add(4)?;
+            messages.extend(parse_packet(rest.get(4..end)?)?);
+            rest = &rest[end..];
+        }
+
+        return Some(messages);
+    }
+
+    let (address, mut rest) = read_string(data)?;
+    if !address.starts_with('/') {
+        return None;
+    }
+
+    // Very old clients omit the type tag string entirely
+    let tags = if rest.first() == Some(&b',') {
+        let (tags, after) = read_string(rest)?;
+        rest = after;
+        tags
+    } else {
+        String::from(",")
+    };
+
+    let mut args = Vec::new();
+    for tag in tags.chars().skip(1) {
+        let arg = match tag {
+            'i' => {
+                let value = i32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+                rest = &rest[4..];
+                Arg::Int(value)
+            }
+            'f' => {
+                let value = f32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+                rest = &rest[4..];
+                Arg::Float(value)
+            }
+            's' => {
+                let (value, after) = read_string(rest)?;
+                rest = after;
+                Arg::String(value)
+            }
+            'T' => Arg::Bool(true),
+            'F' => Arg::Bool(false),
+            _ => return None,
+        };
+        args.push(arg);
+    }
+
+    Some(vec![Message { address, args }])
+}
+
+/// Read a null terminated string padded to a multiple of 4 bytes
+fn read_string(data: &[u8]) -> Option<(String, &[u8])> {
+    let end = data.iter().position(|b| *b == 0)?;
+    let string = std::str::from_utf8(&data[..end]).ok()?.to_owned();
+    let padded = (end + 4) & !3;
+
+    Some((string, data.get(padded..)?))
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    out.extend_from_slice(string.as_bytes());
+    let padding = 4 - string.len() % 4;
+    out.resize(out.len() + padding, 0);
+}
+
+fn encode_message(message: &Message) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_string(&mut out, &message.address);
+
+    let mut tags = String::from(",");
+    let mut args = Vec::new();
+    for arg in &message.args {
+        match arg {
+            Arg::Int(x) => {
+                tags.push('i');
+                args.extend_from_slice(&x.to_be_bytes());
+            }
+            Arg::Float(x) => {
+                tags.push('f');
+                args.extend_from_slice(&x.to_be_bytes());
+            }
+            Arg::String(x) => {
+                tags.push('s');
+                write_string(&mut args, x);
+            }
+            Arg::Bool(x) => tags.push(if *x { 'T' } else { 'F' }),
+        }
+    }
+
+    write_string(&mut out, &tags);
+    out.extend(args);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::{effects::EffectKind, fade::Transition};
+
+    /// Two lights in the `key` group and one in `fill`
+    fn lights() -> Mutex<Vec<LightGuiState>> {
+        let light = |id: &str, group: &str| {
+            let (tx, _) =
+                watch::channel(Transition::new(LightState::default(), Duration::ZERO, None));
+            LightGuiState::new(id, id, vec![group.to_owned()], LightState::default(), tx)
+        };
+
+        Mutex::new(vec![
+            light("a", "key"),
+            light("b", "key"),
+            light("c", "fill"),
+        ])
+    }
+
+    fn intensities(lights: &Mutex<Vec<LightGuiState>>) -> Vec<u8> {
+        lights
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|light| light.state().intensity)
+            .collect()
+    }
+
+    fn message(address: &str, args: Vec<Arg>) -> Message {
+        Message {
+            address: address.to_owned(),
+            args,
+        }
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = b"#bundle\0".to_vec();
+        out.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            out.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            out.extend_from_slice(element);
+        }
+
+        out
+    }
+
+    #[test]
+    fn parses_message() {
+        let sent = message(
+            "/gvm/all/hsi",
+            vec![
+                Arg::Int(20),
+                Arg::Float(0.5),
+                Arg::String("candle".to_owned()),
+                Arg::Bool(true),
+                Arg::Bool(false),
+            ],
+        );
+
+        assert_eq!(parse_packet(&encode_message(&sent)), Some(vec![sent]));
+    }
+
+    #[test]
+    fn parses_message_without_type_tags() {
+        let mut data = Vec::new();
+        write_string(&mut data, "/gvm/all/on");
+
+        assert_eq!(
+            parse_packet(&data),
+            Some(vec![message("/gvm/all/on", Vec::new())])
+        );
+    }
+
+    #[test]
+    fn parses_nested_bundles() {
+        let first = message("/gvm/key/intensity", vec![Arg::Int(40)]);
+        let second = message("/gvm/fill/off", Vec::new());
+        let inner = bundle(&[encode_message(&second)]);
+
+        assert_eq!(
+            parse_packet(&bundle(&[encode_message(&first), inner])),
+            Some(vec![first, second])
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let data = bundle(&[encode_message(&message(
+            "/gvm/all/hsi",
+            vec![Arg::Int(1), Arg::String("x".to_owned())],
+        ))]);
+
+        for len in 0..data.len() {
+            // Cutting off every element leaves a valid empty bundle
+            if len == 16 {
+                continue;
+            }
+            assert_eq!(parse_packet(&data[..len]), None, "length {len}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_bundle_sizes() {
+        for size in [-1, i32::MIN, i32::MAX] {
+            let mut data = bundle(&[]);
+            data.extend_from_slice(&size.to_be_bytes());
+            data.extend_from_slice(&[0; 8]);
+
+            assert_eq!(parse_packet(&data), None, "size {size}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_type_tags() {
+        let mut data = Vec::new();
+        write_string(&mut data, "/gvm/all/on");
+        write_string(&mut data, ",b");
+
+        assert_eq!(parse_packet(&data), None);
+    }
+
+    #[test]
+    fn routes_to_lights_groups_and_all() {
+        let lights = lights();
+
+        handle_message(&message("/gvm/b/intensity", vec![Arg::Int(40)]), &lights);
+        assert_eq!(intensities(&lights), [10, 40, 10]);
+
+        handle_message(
+            &message("/gvm/key/intensity", vec![Arg::Float(55.4)]),
+            &lights,
+        );
+        assert_eq!(intensities(&lights), [55, 55, 10]);
+
+        handle_message(&message("/gvm/all/intensity", vec![Arg::Int(250)]), &lights);
+        assert_eq!(intensities(&lights), [100, 100, 100]);
+    }
+
+    #[test]
+    fn applies_methods() {
+        let mut state = LightState::default();
+
+        assert!(apply("power", &[Arg::Bool(false)], &mut state));
+        assert!(!state.enabled);
+
+        assert!(apply("kelvin", &[Arg::Int(4460)], &mut state));
+        assert_eq!(
+            (state.temperature, state.mode.clone()),
+            (45, LightMode::Cct)
+        );
+
+        let hsi = [Arg::Int(200), Arg::Float(50.0), Arg::Int(-5)];
+        assert!(apply("hsi", &hsi, &mut state));
+        assert_eq!(
+            (
+                state.hue,
+                state.saturation,
+                state.intensity,
+                state.mode.clone()
+            ),
+            (HUE_RANGE - 1, 50, 0, LightMode::Hsi)
+        );
+
+        assert!(apply(
+            "scene",
+            &[Arg::String("candle".to_owned())],
+            &mut state
+        ));
+        assert_eq!(state.scene, Scene::Candle);
+        assert!(apply("scene", &[Arg::Int(1)], &mut state));
+        assert_eq!(
+            (state.scene, state.mode),
+            (Scene::Lightning, LightMode::Scene)
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_addresses_and_wrong_arguments() {
+        let lights = lights();
+        let untouched = intensities(&lights);
+
+        for sent in [
+            message("/other/all/intensity", vec![Arg::Int(40)]),
+            message("/gvm/intensity", vec![Arg::Int(40)]),
+            message("/gvm/nobody/intensity", vec![Arg::Int(40)]),
+            message("/gvm/all/brightness", vec![Arg::Int(40)]),
+            message("/gvm/all/intensity", vec![Arg::String("40".to_owned())]),
+            message("/gvm/all/intensity", Vec::new()),
+            message("/gvm/all/hsi", vec![Arg::Int(10), Arg::Int(20)]),
+            message("/gvm/all/effect", vec![Arg::String("fireworks".to_owned())]),
+            message("/gvm/all/effect", vec![Arg::Int(1)]),
+        ] {
+            assert_eq!(handle_message(&sent, &lights), Vec::new(), "{sent:?}");
+            assert_eq!(intensities(&lights), untouched, "{sent:?}");
+        }
+
+        let mut state = LightState::default();
+        assert!(!apply("scene", &[Arg::Int(9)], &mut state));
+        assert!(!apply(
+            "scene",
+            &[Arg::String("sunset".to_owned())],
+            &mut state
+        ));
+        assert_eq!(state, LightState::default());
+    }
+
+    #[test]
+    fn replies_with_the_state_of_each_matching_light() {
+        let lights = lights();
+
+        let replies = handle_message(&message("/gvm/key/state", Vec::new()), &lights);
+        let addresses: Vec<_> = replies.iter().map(|reply| reply.address.as_str()).collect();
+        assert_eq!(addresses, ["/gvm/a/state", "/gvm/b/state"]);
+    }
+
+    #[test]
+    fn starts_and_stops_effects() {
+        let lights = lights();
+        let effect = |lights: &Mutex<Vec<LightGuiState>>| {
+            lights
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|light| light.effect().map(|effect| effect.kind))
+                .collect::<Vec<_>>()
+        };
+
+        let start = message("/gvm/fill/effect", vec![Arg::String("strobe".to_owned())]);
+        handle_message(&start, &lights);
+        assert_eq!(effect(&lights), [None, None, Some(EffectKind::Strobe)]);
+
+        let stop = message("/gvm/all/effect", vec![Arg::String("stop".to_owned())]);
+        handle_message(&stop, &lights);
+        assert_eq!(effect(&lights), [None, None, None]);
+    }
+}
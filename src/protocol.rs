@@ -1,9 +1,21 @@
-use std::fmt::Debug;
+use std::{
+    fmt::{Debug, Display},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
 
 const HEADER: [u8; 7] = [0x4c, 0x54, 0x09, 0x00, 0x30, 0x57, 0x00];
 
 pub type WireMessage = [u8; 12];
 
+/// Every packet carries this byte between the command and the argument
+const CONSTANT: u8 = 0x01;
+
+/// Hues run from 0 up to (but not including) this, wrapping around from the
+/// top of the range back to red at 0. Anything higher turns the light off.
+pub const HUE_RANGE: u8 = 0x53;
+
 pub trait Packable: Debug {
     fn pack(&self) -> Envelope;
 
@@ -12,37 +24,178 @@ pub trait Packable: Debug {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 #[allow(dead_code)]
 pub enum PowerCommand {
     On,
     Off,
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ModeCommand {
     Cct,
     Hsi,
     Scene,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum HsiCommand {
     Hue(u8),
     Saturation(u8),
     Intensity(u8),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 /// Set the temperature (100s of Kelvin) - range [32, 56]
 pub struct ColorTemperatureCommand(pub u8);
 
+/// Built-in effects which the light can play back while in scene mode
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scene {
+    Lightning,
+    CopCar,
+    Candle,
+    Tv,
+    BadBulb,
+    Party,
+    Disco,
+    Paparazzi,
+}
+
+impl Scene {
+    pub const ALL: [Scene; 8] = [
+        Scene::Lightning,
+        Scene::CopCar,
+        Scene::Candle,
+        Scene::Tv,
+        Scene::BadBulb,
+        Scene::Party,
+        Scene::Disco,
+        Scene::Paparazzi,
+    ];
+
+    /// The argument used to select this scene with the `0x07` command
+    pub fn id(self) -> u8 {
+        match self {
+            Scene::Lightning => 0x01,
+            Scene::CopCar => 0x02,
+            Scene::Candle => 0x03,
+            Scene::Tv => 0x04,
+            Scene::BadBulb => 0x05,
+            Scene::Party => 0x06,
+            Scene::Disco => 0x07,
+            Scene::Paparazzi => 0x08,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|scene| scene.id() == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scene::Lightning => "Lightning",
+            Scene::CopCar => "Cop Car",
+            Scene::Candle => "Candle",
+            Scene::Tv => "TV",
+            Scene::BadBulb => "Bad Bulb",
+            Scene::Party => "Party",
+            Scene::Disco => "Disco",
+            Scene::Paparazzi => "Paparazzi",
+        }
+    }
+}
+
+impl FromStr for Scene {
+    type Err = String;
+
+    /// Accepts scene names regardless of case or separators, e.g. `cop-car`,
+    /// `Cop Car` and `copcar` are all `Scene::CopCar`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let normalize = |name: &str| {
+            name.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        };
+
+        let wanted = normalize(s);
+        Self::ALL
+            .into_iter()
+            .find(|scene| normalize(scene.name()) == wanted)
+            .ok_or_else(|| format!("unknown scene '{s}'"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneCommand {
+    Pick(Scene),
+
+    /// Time between each color transition in the scene (100s of ms) - range
+    /// [1, 50]
+    Interval(u8),
+}
+
+/// Any command understood by the light, as decoded from a `WireMessage`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Power(PowerCommand),
+    Mode(ModeCommand),
+    Hsi(HsiCommand),
+    ColorTemperature(ColorTemperatureCommand),
+    Scene(SceneCommand),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Envelope {
     command: u8,
     arg: u8,
 }
 
+/// Reasons a packet could not be turned back into a `Command`
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// Packets are always exactly 12 bytes
+    Length(usize),
+    Header([u8; 7]),
+    Constant(u8),
+    Checksum {
+        expected: u16,
+        actual: u16,
+    },
+    UnknownCommand(u8),
+    InvalidArgument {
+        command: u8,
+        arg: u8,
+    },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Length(len) => write!(f, "expected a 12 byte packet, got {len} bytes"),
+            Self::Header(header) => write!(f, "unrecognized packet header {header:02x?}"),
+            Self::Constant(byte) => write!(f, "expected constant byte 0x01, got {byte:#04x}"),
+            Self::Checksum { expected, actual } => write!(
+                f,
+                "checksum mismatch: packet says {actual:#06x}, computed {expected:#06x}"
+            ),
+            Self::UnknownCommand(command) => write!(f, "unknown command {command:#04x}"),
+            Self::InvalidArgument { command, arg } => {
+                write!(f, "invalid argument {arg:#04x} for command {command:#04x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Parse a packet received from (or captured on the way to) a light
+pub fn decode(data: &[u8]) -> Result<Command, DecodeError> {
+    Envelope::from_wire(data)?.unpack()
+}
+
 impl Packable for ColorTemperatureCommand {
     fn pack(&self) -> Envelope {
         Envelope {
@@ -98,7 +251,97 @@ impl Packable for HsiCommand {
     }
 }
 
+impl Packable for SceneCommand {
+    fn pack(&self) -> Envelope {
+        match self {
+            Self::Pick(scene) => Envelope {
+                command: 0x07,
+                arg: scene.id(),
+            },
+            Self::Interval(interval) => Envelope {
+                command: 0x08,
+                arg: *interval,
+            },
+        }
+    }
+}
+
+impl Packable for Command {
+    fn pack(&self) -> Envelope {
+        match self {
+            Self::Power(cmd) => cmd.pack(),
+            Self::Mode(cmd) => cmd.pack(),
+            Self::Hsi(cmd) => cmd.pack(),
+            Self::ColorTemperature(cmd) => cmd.pack(),
+            Self::Scene(cmd) => cmd.pack(),
+        }
+    }
+}
+
 impl Envelope {
+    /// Validate the header and checksum of a packet and extract the command and
+    /// argument from it
+    pub fn from_wire(data: &[u8]) -> Result<Self, DecodeError> {
+        let wire: &WireMessage = data
+            .try_into()
+            .map_err(|_| DecodeError::Length(data.len()))?;
+
+        if wire[..7] != HEADER {
+            let mut header = [0; 7];
+            header.copy_from_slice(&wire[..7]);
+            return Err(DecodeError::Header(header));
+        }
+
+        if wire[8] != CONSTANT {
+            return Err(DecodeError::Constant(wire[8]));
+        }
+
+        let expected = crc_16_xmodem(&wire[..10]);
+        let actual = u16::from_be_bytes([wire[10], wire[11]]);
+        if expected != actual {
+            return Err(DecodeError::Checksum { expected, actual });
+        }
+
+        Ok(Envelope {
+            command: wire[7],
+            arg: wire[9],
+        })
+    }
+
+    /// Interpret the command and argument as a typed `Command`
+    pub fn unpack(self) -> Result<Command, DecodeError> {
+        let invalid = DecodeError::InvalidArgument {
+            command: self.command,
+            arg: self.arg,
+        };
+
+        let command = match self.command {
+            0x00 => match self.arg {
+                0x00 => Command::Power(PowerCommand::Off),
+                0x01 => Command::Power(PowerCommand::On),
+                _ => return Err(invalid),
+            },
+            0x02 => Command::Hsi(HsiCommand::Intensity(self.arg)),
+            0x03 => Command::ColorTemperature(ColorTemperatureCommand(self.arg)),
+            0x04 => Command::Hsi(HsiCommand::Hue(self.arg)),
+            0x05 => Command::Hsi(HsiCommand::Saturation(self.arg)),
+            0x06 => match self.arg {
+                0x01 => Command::Mode(ModeCommand::Cct),
+                0x02 => Command::Mode(ModeCommand::Hsi),
+                0x03 => Command::Mode(ModeCommand::Scene),
+                _ => return Err(invalid),
+            },
+            0x07 => match Scene::from_id(self.arg) {
+                Some(scene) => Command::Scene(SceneCommand::Pick(scene)),
+                None => return Err(invalid),
+            },
+            0x08 => Command::Scene(SceneCommand::Interval(self.arg)),
+            command => return Err(DecodeError::UnknownCommand(command)),
+        };
+
+        Ok(command)
+    }
+
     pub fn to_wire(self) -> WireMessage {
         let mut wire = [0; 12];
 
@@ -106,7 +349,7 @@ impl Envelope {
             wire[i] = *x;
         }
         wire[7] = self.command;
-        wire[8] = 0x01;
+        wire[8] = CONSTANT;
         wire[9] = self.arg;
 
         let [b0, b1] = crc_16_xmodem(&wire[..10]).to_be_bytes();
@@ -118,7 +361,8 @@ impl Envelope {
     }
 }
 
-fn crc_16_xmodem(data: &[u8]) -> u16 {
+/// The checksum stored in the last two bytes of every packet, big endian
+pub fn crc_16_xmodem(data: &[u8]) -> u16 {
     const CRC16: &[u16] = &[
         0x0000, 0x1021, 0x2042, 0x3063, 0x4084, 0x50a5, 0x60c6, 0x70e7, 0x8108, 0x9129, 0xa14a,
         0xb16b, 0xc18c, 0xd1ad, 0xe1ce, 0xf1ef, 0x1231, 0x0210, 0x3273, 0x2252, 0x52b5, 0x4294,
@@ -154,3 +398,118 @@ fn crc_16_xmodem(data: &[u8]) -> u16 {
 
     crc
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_command() -> Vec<Command> {
+        let mut commands = vec![
+            Command::Power(PowerCommand::On),
+            Command::Power(PowerCommand::Off),
+            Command::Mode(ModeCommand::Cct),
+            Command::Mode(ModeCommand::Hsi),
+            Command::Mode(ModeCommand::Scene),
+            Command::Hsi(HsiCommand::Hue(HUE_RANGE - 1)),
+            Command::Hsi(HsiCommand::Saturation(100)),
+            Command::Hsi(HsiCommand::Intensity(0)),
+            Command::ColorTemperature(ColorTemperatureCommand(44)),
+            Command::Scene(SceneCommand::Interval(50)),
+        ];
+        commands.extend(
+            Scene::ALL
+                .into_iter()
+                .map(|scene| Command::Scene(SceneCommand::Pick(scene))),
+        );
+
+        commands
+    }
+
+    /// A valid packet with the given command and argument bytes
+    fn packet(command: u8, arg: u8) -> WireMessage {
+        Envelope { command, arg }.to_wire()
+    }
+
+    /// The sample packet from protocol.md, setting the saturation to 5%
+    const DOCUMENTED_PACKET: WireMessage = [
+        0x4c, 0x54, 0x09, 0x00, 0x30, 0x57, 0x00, 0x05, 0x01, 0x05, 0x89, 0xab,
+    ];
+
+    #[test]
+    fn encodes_documented_packet() {
+        assert_eq!(HsiCommand::Saturation(5).to_wire(), DOCUMENTED_PACKET);
+    }
+
+    #[test]
+    fn decodes_documented_packet() {
+        assert_eq!(
+            decode(&DOCUMENTED_PACKET),
+            Ok(Command::Hsi(HsiCommand::Saturation(5)))
+        );
+    }
+
+    #[test]
+    fn round_trips_every_command() {
+        for command in every_command() {
+            assert_eq!(decode(&command.to_wire()), Ok(command));
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let wire = PowerCommand::On.to_wire();
+
+        assert_eq!(decode(&wire[..11]), Err(DecodeError::Length(11)));
+        assert_eq!(decode(&[]), Err(DecodeError::Length(0)));
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let mut wire = PowerCommand::On.to_wire();
+        wire[0] = 0xff;
+
+        let mut header = HEADER;
+        header[0] = 0xff;
+        assert_eq!(decode(&wire), Err(DecodeError::Header(header)));
+    }
+
+    #[test]
+    fn rejects_bad_constant() {
+        let mut wire = PowerCommand::On.to_wire();
+        wire[8] = 0x02;
+
+        assert_eq!(decode(&wire), Err(DecodeError::Constant(0x02)));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut wire = DOCUMENTED_PACKET;
+        wire[11] ^= 0xff;
+
+        assert_eq!(
+            decode(&wire),
+            Err(DecodeError::Checksum {
+                expected: 0x89ab,
+                actual: 0x8954,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        assert_eq!(
+            decode(&packet(0x01, 0x00)),
+            Err(DecodeError::UnknownCommand(0x01))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        for (command, arg) in [(0x00, 0x02), (0x06, 0x00), (0x07, 0x00), (0x07, 0x09)] {
+            assert_eq!(
+                decode(&packet(command, arg)),
+                Err(DecodeError::InvalidArgument { command, arg })
+            );
+        }
+    }
+}
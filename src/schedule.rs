@@ -0,0 +1,416 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{
+    DateTime, Datelike, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+    Weekday,
+};
+use eyre::{bail, eyre, Result};
+use gvm::{
+    protocol::{Scene, HUE_RANGE},
+    LightMode, LightState,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{config::Config, gui::LightGuiState};
+
+/// How often rules are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Checks further apart than this mean the clock jumped (e.g. the machine was
+/// asleep), and rules that fell in the gap are skipped rather than all run at
+/// once
+const MAX_CHECK_GAP: chrono::Duration = chrono::Duration::minutes(1);
+
+/// Where the lights are, for working out sunrise and sunset
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    /// Degrees, north positive
+    pub latitude: f64,
+    /// Degrees, east positive
+    pub longitude: f64,
+}
+
+/// A change to make to some lights at a time of day
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// Days the rule runs on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+
+    pub at: At,
+
+    /// Light id or group name, every light if unset
+    #[serde(default)]
+    pub light: Option<String>,
+
+    /// Seconds to fade to the new state over
+    #[serde(default)]
+    pub fade: f32,
+
+    #[serde(flatten)]
+    pub change: StateChange,
+}
+
+/// A time of day, either on the clock or relative to the sun
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum At {
+    Clock(NaiveTime),
+    /// Minutes after sunrise, negative for before
+    Sunrise(i64),
+    /// Minutes after sunset, negative for before
+    Sunset(i64),
+}
+
+impl FromStr for At {
+    type Err = eyre::Report;
+
+    /// Parses `HH:MM`, `sunrise`, `sunset`, or either of the last two with an
+    /// offset in minutes, e.g. `sunset-30`
+    fn from_str(s: &str) -> Result<Self> {
+        let offset = |rest: &str| -> Result<i64> {
+            if rest.is_empty() {
+                return Ok(0);
+            }
+            if !rest.starts_with(['+', '-']) {
+                bail!("expected +<minutes> or -<minutes> after the sun event in '{s}'");
+            }
+            rest.parse()
+                .map_err(|e| eyre!("invalid offset in '{s}': {e}"))
+        };
+
+        if let Some(rest) = s.strip_prefix("sunrise") {
+            return Ok(Self::Sunrise(offset(rest)?));
+        }
+        if let Some(rest) = s.strip_prefix("sunset") {
+            return Ok(Self::Sunset(offset(rest)?));
+        }
+
+        NaiveTime::parse_from_str(s, "%H:%M")
+            .map(Self::Clock)
+            .map_err(|_| eyre!("'{s}' should be HH:MM, sunrise or sunset"))
+    }
+}
+
+impl Display for At {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (event, offset) = match self {
+            Self::Clock(time) => return write!(f, "{}", time.format("%H:%M")),
+            Self::Sunrise(offset) => ("sunrise", *offset),
+            Self::Sunset(offset) => ("sunset", *offset),
+        };
+
+        match offset {
+            0 => write!(f, "{event}"),
+            offset => write!(f, "{event}{offset:+}"),
+        }
+    }
+}
+
+impl TryFrom<String> for At {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<At> for String {
+    fn from(value: At) -> Self {
+        value.to_string()
+    }
+}
+
+/// Settings to change on a light, leaving everything else as it is
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct StateChange {
+    pub enabled: Option<bool>,
+
+    /// Inferred from the other settings if unset - a temperature switches to
+    /// CCT, a hue or saturation to HSI and a scene to scene mode
+    pub mode: Option<LightMode>,
+    pub intensity: Option<u8>,
+    pub temperature: Option<u8>,
+    pub hue: Option<u8>,
+    pub saturation: Option<u8>,
+    pub scene: Option<Scene>,
+}
+
+impl StateChange {
+    pub fn apply(&self, state: &mut LightState) {
+        if let Some(enabled) = self.enabled {
+            state.enabled = enabled;
+        }
+        if let Some(intensity) = self.intensity {
+            state.intensity = intensity.min(100);
+        }
+        if let Some(temperature) = self.temperature {
+            state.temperature = temperature.clamp(32, 56);
+            state.mode = LightMode::Cct;
+        }
+        if let Some(hue) = self.hue {
+            state.hue = hue.min(HUE_RANGE - 1);
+            state.mode = LightMode::Hsi;
+        }
+        if let Some(saturation) = self.saturation {
+            state.saturation = saturation.min(100);
+            state.mode = LightMode::Hsi;
+        }
+        if let Some(scene) = self.scene {
+            state.scene = scene;
+            state.mode = LightMode::Scene;
+        }
+        if let Some(mode) = &self.mode {
+            state.mode = mode.clone();
+        }
+    }
+}
+
+impl Rule {
+    /// When this rule runs on `date`, if it runs that day at all
+    fn time_on(&self, date: NaiveDate, location: Option<Location>) -> Option<DateTime<Local>> {
+        if !self.days.is_empty() && !self.days.contains(&date.weekday()) {
+            return None;
+        }
+
+        let (sun_time, offset) = match self.at {
+            At::Clock(time) => {
+                return first_valid(date.and_time(time), |x| Local.from_local_datetime(x));
+            }
+            At::Sunrise(offset) => (sun_times(date, location?)?.0, offset),
+            At::Sunset(offset) => (sun_times(date, location?)?.1, offset),
+        };
+
+        Some((sun_time + chrono::Duration::minutes(offset)).with_timezone(&Local))
+    }
+}
+
+/// The first moment at or after `datetime` that exists, given how to resolve a
+/// clock time to one. A time skipped when the clocks go forward resolves to the
+/// moment they go forward, so rules in the gap still run that day.
+fn first_valid<T>(
+    datetime: NaiveDateTime,
+    resolve: impl Fn(&NaiveDateTime) -> LocalResult<T>,
+) -> Option<T> {
+    // No time zone skips more than a few hours
+    (0..=4 * 60)
+        .find_map(|minutes| resolve(&(datetime + chrono::Duration::minutes(minutes))).earliest())
+}
+
+/// Run the config's schedule forever, applying each rule to the lights it
+/// covers when its time comes around
+pub(crate) async fn run(config: Arc<Mutex<Config>>, lights: Arc<Mutex<Vec<LightGuiState>>>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut last_check = Local::now();
+    let mut warned_no_location = false;
+
+    loop {
+        interval.tick().await;
+        let now = Local::now();
+
+        if now - last_check > MAX_CHECK_GAP || now < last_check {
+            warn!(%last_check, %now, "clock jumped, skipping scheduled rules in between");
+            last_check = now;
+            continue;
+        }
+
+        let (rules, location) = {
+            let config = config.lock().unwrap();
+            (config.schedule.clone(), config.location)
+        };
+
+        if location.is_none()
+            && !warned_no_location
+            && rules.iter().any(|rule| !matches!(rule.at, At::Clock(_)))
+        {
+            warn!("schedule has sunrise or sunset rules but no location is configured");
+            warned_no_location = true;
+        }
+
+        let mut dates = vec![last_check.date_naive()];
+        if now.date_naive() != last_check.date_naive() {
+            dates.push(now.date_naive());
+        }
+
+        for rule in &rules {
+            let due = dates.iter().any(|date| {
+                rule.time_on(*date, location)
+                    .is_some_and(|time| last_check < time && time <= now)
+            });
+
+            if due {
+                info!(at = %rule.at, light = ?rule.light, "running scheduled rule");
+                apply(rule, &lights);
+            }
+        }
+
+        last_check = now;
+    }
+}
+
+fn apply(rule: &Rule, lights: &Mutex<Vec<LightGuiState>>) {
+    let fade = Duration::try_from_secs_f32(rule.fade).unwrap_or_default();
+
+    for light in lights
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|light| rule.light.as_deref().is_none_or(|x| light.matches(x)))
+    {
+        let mut state = light.state().clone();
+        rule.change.apply(&mut state);
+        light.fade_to_automatically(state, fade);
+    }
+}
+
+/// Sunrise and sunset on `date` at `location`, or `None` during polar day or
+/// night. Uses the sunrise equation, which is accurate to a minute or so.
+pub(crate) fn sun_times(
+    date: NaiveDate,
+    location: Location,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    const J2000: f64 = 2451545.0;
+    const UNIX_EPOCH_JULIAN_DAY: f64 = 2440587.5;
+
+    let days_since_j2000 = (date - NaiveDate::from_ymd_opt(2000, 1, 1)?).num_days() as f64;
+    let mean_solar_time = days_since_j2000 - location.longitude / 360.0;
+
+    let mean_anomaly = (357.5291 + 0.98560028 * mean_solar_time).rem_euclid(360.0);
+    let m = mean_anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.0200 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic_longitude = (mean_anomaly + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+
+    let transit =
+        J2000 + mean_solar_time + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    let to_utc = |julian_day: f64| {
+        let seconds = (julian_day - UNIX_EPOCH_JULIAN_DAY) * 86400.0;
+        Utc.timestamp_opt(seconds.round() as i64, 0).single()
+    };
+
+    Some((
+        to_utc(transit - hour_angle / 360.0)?,
+        to_utc(transit + hour_angle / 360.0)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Timelike;
+
+    use super::*;
+
+    fn rule(days: Vec<Weekday>, at: At) -> Rule {
+        Rule {
+            days,
+            at,
+            light: None,
+            fade: 0.0,
+            change: StateChange::default(),
+        }
+    }
+
+    #[test]
+    fn parse_at() {
+        let noon = NaiveTime::from_hms_opt(12, 5, 0).unwrap();
+        assert_eq!("12:05".parse::<At>().unwrap(), At::Clock(noon));
+        assert_eq!("sunrise".parse::<At>().unwrap(), At::Sunrise(0));
+        assert_eq!("sunrise+30".parse::<At>().unwrap(), At::Sunrise(30));
+        assert_eq!("sunset-15".parse::<At>().unwrap(), At::Sunset(-15));
+
+        for bad in ["", "7pm", "25:00", "12:60", "sunrise30", "sunset+x", "noon"] {
+            assert!(bad.parse::<At>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn at_round_trips_through_strings() {
+        for at in ["06:30", "sunrise", "sunrise+30", "sunset-15"] {
+            assert_eq!(at.parse::<At>().unwrap().to_string(), at);
+        }
+    }
+
+    #[test]
+    fn sun_times_match_published_times() {
+        // London on the 2024 summer solstice: sunrise 04:43 BST and sunset
+        // 21:21 BST, according to timeanddate.com
+        let london = Location {
+            latitude: 51.5072,
+            longitude: -0.1276,
+        };
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let (sunrise, sunset) = sun_times(date, london).unwrap();
+
+        let close_to = |time: DateTime<Utc>, hour, minute| {
+            let expected = Utc.from_utc_datetime(&date.and_hms_opt(hour, minute, 0).unwrap());
+            (time - expected).num_minutes().abs() <= 3
+        };
+        assert!(close_to(sunrise, 3, 43), "{sunrise}");
+        assert!(close_to(sunset, 20, 21), "{sunset}");
+
+        // The sun doesn't set in Tromsø in June
+        let tromso = Location {
+            latitude: 69.65,
+            longitude: 18.96,
+        };
+        assert_eq!(sun_times(date, tromso), None);
+    }
+
+    #[test]
+    fn rules_only_run_on_their_days() {
+        let at = At::Clock(NaiveTime::from_hms_opt(12, 0, 0).unwrap());
+        // A Monday
+        let monday = NaiveDate::from_ymd_opt(2024, 6, 17).unwrap();
+        let tuesday = monday.succ_opt().unwrap();
+
+        let weekly = rule(vec![Weekday::Mon, Weekday::Fri], at);
+        assert!(weekly.time_on(monday, None).is_some());
+        assert!(weekly.time_on(tuesday, None).is_none());
+
+        let daily = rule(Vec::new(), at);
+        assert!(daily.time_on(monday, None).is_some());
+        assert!(daily.time_on(tuesday, None).is_some());
+
+        // Sun rules can't run without knowing where the sun is
+        assert!(rule(Vec::new(), At::Sunset(0))
+            .time_on(monday, None)
+            .is_none());
+    }
+
+    #[test]
+    fn times_skipped_by_the_clocks_going_forward_run_when_they_do() {
+        // Clocks going forward from 02:00 to 03:00
+        let date = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        let resolve = |x: &NaiveDateTime| {
+            if (2..3).contains(&x.hour()) {
+                LocalResult::None
+            } else {
+                LocalResult::Single(*x)
+            }
+        };
+
+        let at = |hour, minute| date.and_hms_opt(hour, minute, 0).unwrap();
+        assert_eq!(first_valid(at(2, 30), resolve), Some(at(3, 0)));
+        assert_eq!(first_valid(at(1, 30), resolve), Some(at(1, 30)));
+        assert_eq!(first_valid(at(3, 0), resolve), Some(at(3, 0)));
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use eyre::Result;
+use gvm::LightState;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{sleep_until, Instant},
+};
+use tracing::{info, warn};
+
+use crate::{config::write_atomically, gui::LightGuiState};
+
+/// An ordered list of cues for a production, stored as a TOML file
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct Show {
+    pub cues: Vec<Cue>,
+}
+
+/// A single step of a show
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Cue {
+    pub name: String,
+
+    /// Light id to the state that light should be in. Lights missing from the
+    /// cue are left as they are.
+    pub lights: BTreeMap<String, LightState>,
+
+    /// Seconds to fade into the cue over
+    pub fade: f32,
+
+    /// Seconds to wait after GO before starting the fade
+    pub delay: f32,
+
+    /// Go to the next cue by itself once this one has finished fading in
+    pub follow: bool,
+}
+
+impl Cue {
+    /// Snapshot the given lights as a new cue
+    pub fn capture<'a>(lights: impl IntoIterator<Item = &'a LightGuiState>) -> Self {
+        Self {
+            lights: lights
+                .into_iter()
+                .map(|light| (light.id().to_owned(), light.state().clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Show {
+    /// Load the show at `path`, or an empty show if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Show::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_atomically(path, &toml::to_string_pretty(self)?)
+    }
+
+    /// Find a cue by its number (counting from 1) or its name
+    #[cfg(unix)]
+    pub fn find(&self, cue: &str) -> Option<usize> {
+        match cue.parse::<usize>() {
+            Ok(number) => (1..=self.cues.len()).contains(&number).then(|| number - 1),
+            Err(_) => self.cues.iter().position(|x| x.name == cue),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum CueCommand {
+    Go,
+    Back,
+    Jump(usize),
+}
+
+/// Plays a show back on the lights. Cheap to clone, every clone controls the
+/// same playback.
+#[derive(Clone)]
+pub(crate) struct Sequencer {
+    playback: Arc<Mutex<Playback>>,
+    tx: UnboundedSender<CueCommand>,
+}
+
+/// What every clone of a `Sequencer` shares with the task running the show
+struct Playback {
+    show: Show,
+    path: PathBuf,
+
+    /// Index of the cue that was last started
+    current: Option<usize>,
+}
+
+/// What to do once the sequencer's timer runs out
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    /// A cue's delay has passed, start fading it in
+    FadeIn(usize),
+
+    /// A cue has finished fading in and follows on to the next one
+    Follow(usize),
+}
+
+impl Sequencer {
+    /// Create a sequencer for the show stored at `path`, returning the task
+    /// which needs to be spawned for cues to actually run
+    pub fn new(
+        path: PathBuf,
+        lights: Arc<Mutex<Vec<LightGuiState>>>,
+    ) -> Result<(Self, impl std::future::Future<Output = ()>)> {
+        let show = Show::load(&path)?;
+        let (tx, rx) = unbounded_channel();
+
+        let sequencer = Self {
+            playback: Arc::new(Mutex::new(Playback {
+                show,
+                path,
+                current: None,
+            })),
+            tx,
+        };
+        let task = run(sequencer.playback.clone(), rx, lights);
+
+        Ok((sequencer, task))
+    }
+
+    /// Start the cue after the current one
+    pub fn go(&self) {
+        _ = self.tx.send(CueCommand::Go);
+    }
+
+    /// Return to the cue before the current one, skipping its delay
+    pub fn back(&self) {
+        _ = self.tx.send(CueCommand::Back);
+    }
+
+    /// Start the cue at `index` straight away
+    pub fn jump(&self, index: usize) {
+        _ = self.tx.send(CueCommand::Jump(index));
+    }
+
+    pub fn show(&self) -> Show {
+        self.playback.lock().unwrap().show.clone()
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.playback.lock().unwrap().current
+    }
+
+    /// Apply a change to the show and save it, logging any failure to save
+    pub fn edit(&self, change: impl FnOnce(&mut Show)) {
+        let mut playback = self.playback.lock().unwrap();
+        change(&mut playback.show);
+
+        if let Some(current) = playback.current {
+            if current >= playback.show.cues.len() {
+                playback.current = None;
+            }
+        }
+
+        if let Err(e) = playback.show.save(&playback.path) {
+            warn!(error = ?e, "failed to save show");
+        }
+    }
+}
+
+/// Run cues as commands arrive, handling delays and auto-follows
+async fn run(
+    playback: Arc<Mutex<Playback>>,
+    mut commands: UnboundedReceiver<CueCommand>,
+    lights: Arc<Mutex<Vec<LightGuiState>>>,
+) {
+    let mut pending: Option<(Instant, Pending)> = None;
+
+    loop {
+        let deadline = pending.map(|(deadline, _)| deadline);
+
+        select! {
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    break;
+                };
+
+                // The playback lock is released before the cue reaches the
+                // lights, since others take the locks the other way around
+                let fade_now = {
+                    let mut playback = playback.lock().unwrap();
+                    let len = playback.show.cues.len();
+
+                    let (index, go) = match command {
+                        CueCommand::Go => (playback.current.map_or(0, |x| x + 1), true),
+                        CueCommand::Back => match playback.current {
+                            Some(x) if x > 0 => (x - 1, false),
+                            _ => continue,
+                        },
+                        CueCommand::Jump(index) => (index, false),
+                    };
+
+                    if index >= len {
+                        info!(cue = index + 1, "no cue to go to");
+                        continue;
+                    }
+
+                    // Anything still waiting belonged to the previous cue
+                    pending = None;
+                    start(&mut playback, index, !go, &mut pending)
+                };
+
+                if let Some((index, cue)) = fade_now {
+                    pending = fade_in(&cue, index, &lights);
+                }
+
+                // Going back shouldn't immediately follow on forwards again
+                if matches!(command, CueCommand::Back) {
+                    pending = None;
+                }
+            }
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let fade_now = {
+                    let mut playback = playback.lock().unwrap();
+
+                    match pending.take() {
+                        Some((_, Pending::FadeIn(index))) => {
+                            playback.show.cues.get(index).cloned().map(|cue| (index, cue))
+                        }
+                        Some((_, Pending::Follow(index))) if index + 1 < playback.show.cues.len() => {
+                            start(&mut playback, index + 1, false, &mut pending)
+                        }
+                        _ => None,
+                    }
+                };
+
+                if let Some((index, cue)) = fade_now {
+                    pending = fade_in(&cue, index, &lights);
+                }
+            }
+        }
+    }
+}
+
+/// Make a cue current. Returns the cue if it should be faded in straight
+/// away, otherwise sets `pending` to wait out its delay first.
+fn start(
+    playback: &mut Playback,
+    index: usize,
+    skip_delay: bool,
+    pending: &mut Option<(Instant, Pending)>,
+) -> Option<(usize, Cue)> {
+    let cue = &playback.show.cues[index];
+    info!(cue = index + 1, name = %cue.name, "GO");
+
+    let delay = Duration::try_from_secs_f32(cue.delay).unwrap_or_default();
+    let cue = cue.clone();
+    playback.current = Some(index);
+
+    if skip_delay || delay.is_zero() {
+        Some((index, cue))
+    } else {
+        *pending = Some((Instant::now() + delay, Pending::FadeIn(index)));
+        None
+    }
+}
+
+/// Send a cue's states to the lights. Returns the follow-on to the next cue, if
+/// the cue has one.
+fn fade_in(
+    cue: &Cue,
+    index: usize,
+    lights: &Mutex<Vec<LightGuiState>>,
+) -> Option<(Instant, Pending)> {
+    let fade = Duration::try_from_secs_f32(cue.fade).unwrap_or_default();
+
+    for light in lights.lock().unwrap().iter_mut() {
+        if let Some(state) = cue.lights.get(light.id()) {
+            light.fade_to(state.clone(), fade);
+        }
+    }
+
+    cue.follow
+        .then(|| (Instant::now() + fade, Pending::Follow(index)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::fade::Transition;
+
+    fn cue(name: &str, intensity: u8) -> Cue {
+        Cue {
+            name: name.into(),
+            lights: [(
+                "a".into(),
+                LightState {
+                    intensity,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    /// Let the sequencer catch up, and the clock move on by `seconds`
+    async fn after(seconds: f32) {
+        tokio::time::sleep(Duration::from_secs_f32(seconds.max(0.001))).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sequencer_runs_cues() {
+        let dir = std::env::temp_dir().join(format!("gvm-show-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("show.toml");
+
+        let show = Show {
+            cues: vec![
+                cue("one", 10),
+                Cue {
+                    delay: 2.0,
+                    fade: 1.0,
+                    follow: true,
+                    ..cue("two", 20)
+                },
+                cue("three", 30),
+            ],
+        };
+        show.save(&path).unwrap();
+
+        let (tx, rx) = watch::channel(Transition::new(LightState::default(), Duration::ZERO, None));
+        let light = LightGuiState::new("a", "A", Vec::new(), LightState::default(), tx);
+        let lights = Arc::new(Mutex::new(vec![light]));
+
+        let (sequencer, task) = Sequencer::new(path, lights.clone()).unwrap();
+        tokio::spawn(task);
+        let intensity = || lights.lock().unwrap()[0].state().intensity;
+
+        sequencer.go();
+        after(0.0).await;
+        assert_eq!(sequencer.current(), Some(0));
+        assert_eq!(intensity(), 10);
+
+        // The second cue waits out its delay before fading in
+        sequencer.go();
+        after(1.9).await;
+        assert_eq!(sequencer.current(), Some(1));
+        assert_eq!(intensity(), 10);
+        after(0.2).await;
+        assert_eq!(intensity(), 20);
+        assert_eq!(rx.borrow().fade, Duration::from_secs(1));
+
+        // then follows on to the third once it has faded in
+        after(1.0).await;
+        assert_eq!(sequencer.current(), Some(2));
+        assert_eq!(intensity(), 30);
+
+        // Going back skips the delay, and doesn't follow on again
+        sequencer.back();
+        after(0.0).await;
+        assert_eq!(sequencer.current(), Some(1));
+        assert_eq!(intensity(), 20);
+        after(5.0).await;
+        assert_eq!(sequencer.current(), Some(1));
+
+        sequencer.jump(0);
+        after(0.0).await;
+        assert_eq!(sequencer.current(), Some(0));
+        assert_eq!(intensity(), 10);
+
+        // Nothing past the last cue
+        sequencer.jump(2);
+        sequencer.go();
+        after(0.0).await;
+        assert_eq!(sequencer.current(), Some(2));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
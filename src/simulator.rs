@@ -0,0 +1,140 @@
+use std::sync::{Arc, Mutex};
+
+use eyre::Result;
+use futures::stream::BoxStream;
+use tracing::{info, warn};
+
+use crate::{
+    protocol::{
+        self, ColorTemperatureCommand, Command, DecodeError, HsiCommand, ModeCommand, PowerCommand,
+        Scene, SceneCommand, WireMessage, HUE_RANGE,
+    },
+    transport::{LightTransport, MemoryTransport},
+};
+
+/// The state of a GVM light as the firmware sees it, built up only from the
+/// packets that have been sent to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedLight {
+    pub powered: bool,
+    pub mode: ModeCommand,
+    pub hue: u8,
+    pub saturation: u8,
+    pub intensity: u8,
+    pub temperature: u8,
+    pub scene: Scene,
+    pub scene_interval: u8,
+}
+
+impl Default for SimulatedLight {
+    fn default() -> Self {
+        Self {
+            powered: true,
+            mode: ModeCommand::Cct,
+            hue: 0,
+            saturation: 100,
+            intensity: 10,
+            temperature: 32,
+            scene: Scene::Lightning,
+            scene_interval: 10,
+        }
+    }
+}
+
+impl SimulatedLight {
+    /// Decode a packet and apply it to the light. Packets which fail to decode
+    /// are ignored by the hardware, so they leave the state untouched here too.
+    pub fn apply(&mut self, data: &[u8]) -> Result<Command, DecodeError> {
+        let command = protocol::decode(data)?;
+
+        match &command {
+            Command::Power(PowerCommand::On) => self.powered = true,
+            Command::Power(PowerCommand::Off) => self.powered = false,
+            Command::Mode(mode) => self.mode = mode.clone(),
+            Command::Hsi(HsiCommand::Hue(hue)) => {
+                self.hue = *hue;
+                if *hue >= HUE_RANGE {
+                    self.powered = false;
+                }
+            }
+            Command::Hsi(HsiCommand::Saturation(saturation)) => self.saturation = *saturation,
+            Command::Hsi(HsiCommand::Intensity(intensity)) => self.intensity = *intensity,
+            Command::ColorTemperature(ColorTemperatureCommand(temperature)) => {
+                self.temperature = *temperature
+            }
+            Command::Scene(SceneCommand::Pick(scene)) => self.scene = *scene,
+            Command::Scene(SceneCommand::Interval(interval)) => self.scene_interval = *interval,
+        }
+
+        Ok(command)
+    }
+}
+
+/// Transport backed by a `SimulatedLight` instead of a radio. Every accepted
+/// packet is echoed back as a notification, and everything written is recorded
+/// by the underlying `MemoryTransport`.
+#[derive(Clone)]
+pub struct SimulatorTransport {
+    memory: MemoryTransport,
+    light: Arc<Mutex<SimulatedLight>>,
+}
+
+impl SimulatorTransport {
+    pub fn new(id: impl Into<String>, mac: Option<[u8; 6]>) -> Self {
+        Self {
+            memory: MemoryTransport::new(id, mac),
+            light: Arc::new(Mutex::new(SimulatedLight::default())),
+        }
+    }
+
+    /// Snapshot of the simulated light's current state
+    pub fn light(&self) -> SimulatedLight {
+        self.light.lock().unwrap().clone()
+    }
+
+    /// Control over connection state and access to the raw packets written
+    pub fn memory(&self) -> &MemoryTransport {
+        &self.memory
+    }
+}
+
+impl LightTransport for SimulatorTransport {
+    fn id(&self) -> String {
+        self.memory.id()
+    }
+
+    async fn write(&self, message: WireMessage) -> Result<()> {
+        self.memory.write(message).await?;
+
+        let result = self.light.lock().unwrap().apply(&message);
+        match result {
+            Ok(command) => {
+                info!(id = %self.id(), ?command, light = ?self.light(), "simulated light updated");
+                self.memory.notify(message.to_vec());
+            }
+            Err(e) => warn!(id = %self.id(), error = %e, "simulated light ignored packet"),
+        }
+
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<BoxStream<'static, Vec<u8>>> {
+        self.memory.notifications().await
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        self.memory.is_connected().await
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        self.memory.reconnect().await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.memory.disconnect().await
+    }
+
+    async fn mac(&self) -> Result<Option<[u8; 6]>> {
+        self.memory.mac().await
+    }
+}
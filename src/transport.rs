@@ -0,0 +1,151 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use eyre::{bail, Result};
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::protocol::WireMessage;
+
+/// The link between the host and a single light. The bluetooth module
+/// implements this on top of btleplug, but anything that can carry
+/// `WireMessage`s to a light (or something pretending to be one) works.
+///
+/// The futures are required to be `Send` so that a connection to any transport
+/// can be spawned onto the runtime.
+pub trait LightTransport: Send + Sync + 'static {
+    /// Identifier for this light used in logs
+    fn id(&self) -> String;
+
+    /// Send a single packet to the light
+    fn write(&self, message: WireMessage) -> impl Future<Output = Result<()>> + Send;
+
+    /// Subscribe to packets sent back by the light
+    fn notifications(&self) -> impl Future<Output = Result<BoxStream<'static, Vec<u8>>>> + Send;
+
+    fn is_connected(&self) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Attempt to re-establish a dropped connection
+    fn reconnect(&self) -> impl Future<Output = Result<()>> + Send;
+
+    fn disconnect(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// The hardware address of the light, if the transport is able to find it
+    fn mac(&self) -> impl Future<Output = Result<Option<[u8; 6]>>> + Send;
+}
+
+/// Transport which never touches a radio - it records every packet written to
+/// it and lets the caller control connection state and inject notifications.
+#[derive(Clone)]
+pub struct MemoryTransport {
+    inner: Arc<MemoryTransportInner>,
+}
+
+struct MemoryTransportInner {
+    id: String,
+    mac: Option<[u8; 6]>,
+    connected: AtomicBool,
+
+    // Reconnection attempts fail while this is set, to mimic a light that is
+    // powered off
+    reject_reconnect: AtomicBool,
+    written: Mutex<Vec<WireMessage>>,
+    notification_tx: UnboundedSender<Vec<u8>>,
+    notification_rx: Mutex<Option<UnboundedReceiver<Vec<u8>>>>,
+}
+
+impl MemoryTransport {
+    pub fn new(id: impl Into<String>, mac: Option<[u8; 6]>) -> Self {
+        let (notification_tx, notification_rx) = unbounded_channel();
+
+        Self {
+            inner: Arc::new(MemoryTransportInner {
+                id: id.into(),
+                mac,
+                connected: AtomicBool::new(true),
+                reject_reconnect: AtomicBool::new(false),
+                written: Mutex::new(Vec::new()),
+                notification_tx,
+                notification_rx: Mutex::new(Some(notification_rx)),
+            }),
+        }
+    }
+
+    /// Every packet written so far, oldest first
+    pub fn written(&self) -> Vec<WireMessage> {
+        self.inner.written.lock().unwrap().clone()
+    }
+
+    /// Remove and return every packet written so far
+    pub fn take_written(&self) -> Vec<WireMessage> {
+        std::mem::take(&mut *self.inner.written.lock().unwrap())
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.inner.connected.store(connected, Ordering::SeqCst);
+    }
+
+    pub fn set_reject_reconnect(&self, reject: bool) {
+        self.inner.reject_reconnect.store(reject, Ordering::SeqCst);
+    }
+
+    /// Queue a packet as though the light had sent it
+    pub fn notify(&self, data: Vec<u8>) {
+        _ = self.inner.notification_tx.send(data);
+    }
+}
+
+impl LightTransport for MemoryTransport {
+    fn id(&self) -> String {
+        self.inner.id.clone()
+    }
+
+    async fn write(&self, message: WireMessage) -> Result<()> {
+        if !self.inner.connected.load(Ordering::SeqCst) {
+            bail!("not connected");
+        }
+
+        self.inner.written.lock().unwrap().push(message);
+
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<BoxStream<'static, Vec<u8>>> {
+        // Only the first subscriber gets the notifications, which is all that
+        // `Light::run` needs
+        match self.inner.notification_rx.lock().unwrap().take() {
+            Some(rx) => Ok(UnboundedReceiverStream::new(rx).boxed()),
+            None => Ok(stream::pending().boxed()),
+        }
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.inner.connected.load(Ordering::SeqCst))
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        if self.inner.reject_reconnect.load(Ordering::SeqCst) {
+            bail!("light is not responding");
+        }
+
+        self.set_connected(true);
+
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.set_connected(false);
+
+        Ok(())
+    }
+
+    async fn mac(&self) -> Result<Option<[u8; 6]>> {
+        Ok(self.inner.mac)
+    }
+}
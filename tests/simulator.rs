@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use gvm::{
+    protocol::{HsiCommand, ModeCommand, Packable, PowerCommand, Scene, HUE_RANGE},
+    simulator::{SimulatedLight, SimulatorTransport},
+    transport::LightTransport,
+    Light, LightMode, LightState,
+};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+const MAC: [u8; 6] = [0x5e, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+async fn light() -> (Light<SimulatorTransport>, SimulatorTransport) {
+    let transport = SimulatorTransport::new("simulated", Some(MAC));
+    let light = Light::new(transport.clone()).await;
+
+    (light, transport)
+}
+
+#[tokio::test]
+async fn setters_reach_the_light() {
+    let (mut light, transport) = light().await;
+    assert_eq!(light.id(), "5e:00:00:00:00:01");
+
+    light.set_hsi(20, 80, 60).await.unwrap();
+    let simulated = transport.light();
+    assert_eq!(simulated.mode, ModeCommand::Hsi);
+    assert_eq!(
+        (simulated.hue, simulated.saturation, simulated.intensity),
+        (20, 80, 60)
+    );
+
+    light.set_cct(44, 30).await.unwrap();
+    let simulated = transport.light();
+    assert_eq!(simulated.mode, ModeCommand::Cct);
+    assert_eq!((simulated.temperature, simulated.intensity), (44, 30));
+
+    light.set_scene(Scene::Party).await.unwrap();
+    let simulated = transport.light();
+    assert_eq!(simulated.mode, ModeCommand::Scene);
+    assert_eq!(simulated.scene, Scene::Party);
+
+    light.power(false).await.unwrap();
+    assert!(!transport.light().powered);
+}
+
+#[tokio::test]
+async fn full_state_matches_the_light() {
+    let (mut light, transport) = light().await;
+
+    let state = LightState {
+        mode: LightMode::Scene,
+        scene: Scene::CopCar,
+        scene_interval: 3,
+        intensity: 75,
+        ..LightState::default()
+    };
+    light.set_state(&state).await.unwrap();
+
+    assert_eq!(
+        transport.light(),
+        SimulatedLight {
+            mode: ModeCommand::Scene,
+            scene: Scene::CopCar,
+            scene_interval: 3,
+            intensity: 75,
+            ..SimulatedLight::default()
+        }
+    );
+}
+
+#[tokio::test]
+async fn out_of_range_hue_turns_the_light_off() {
+    let (light, transport) = light().await;
+
+    light.cmd(HsiCommand::Hue(HUE_RANGE - 1)).await.unwrap();
+    assert!(transport.light().powered);
+
+    light.cmd(HsiCommand::Hue(HUE_RANGE)).await.unwrap();
+    assert!(!transport.light().powered);
+
+    light.cmd(PowerCommand::On).await.unwrap();
+    assert!(transport.light().powered);
+}
+
+#[tokio::test]
+async fn malformed_packets_are_ignored() {
+    let (_light, transport) = light().await;
+
+    let mut wire = HsiCommand::Intensity(99).to_wire();
+    wire[11] ^= 0xff;
+    transport.write(wire).await.unwrap();
+
+    assert_eq!(transport.light(), SimulatedLight::default());
+}
+
+#[tokio::test]
+async fn accepted_packets_are_echoed() {
+    let (light, transport) = light().await;
+    let mut notifications = transport.notifications().await.unwrap();
+
+    light.cmd(HsiCommand::Saturation(12)).await.unwrap();
+
+    assert_eq!(
+        notifications.next().await,
+        Some(HsiCommand::Saturation(12).to_wire().to_vec())
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn run_follows_the_state_stream() {
+    let (light, transport) = light().await;
+
+    let (tx, rx) = unbounded_channel();
+    let task = tokio::spawn(light.run(LightState::default(), UnboundedReceiverStream::new(rx)));
+
+    for intensity in [20, 40, 60] {
+        tx.send(LightState {
+            intensity,
+            ..LightState::default()
+        })
+        .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(transport.light().intensity, 60);
+
+    drop(tx);
+    task.await.unwrap().unwrap();
+}